@@ -0,0 +1,255 @@
+// Parsing of polygons in the GeoJSON and WKT formats
+
+use std::iter::Peekable;
+
+use serde_json::Value;
+
+use crate::geoutil::Polygon;
+
+/// check whether lng/lat is a valid position (in degrees)
+pub fn valid_position(lng: f64, lat: f64) -> bool {
+    lng.is_finite() && lat.is_finite() && lng.abs() <= 180.0 && lat.abs() <= 90.0
+}
+
+/// count the distinct positions of a ring (of valid positions)
+pub fn distinct_positions(ring: &[(f64, f64)]) -> usize {
+    let mut positions = ring.to_vec();
+    positions.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    positions.dedup();
+    positions.len()
+}
+
+/// check the closed rings (the last position repeating the first, as GeoJSON and WKT require) of
+/// the nth polygon and convert them to a Polygon, the first ring being the outer ring
+fn closed_rings_to_polygon(rings: Vec<Vec<(f64, f64)>>, n: usize) -> Result<Polygon, String> {
+    if rings.is_empty() {
+        return Err(format!("polygon {} has no rings", n));
+    }
+
+    let mut checked: Vec<Vec<(f64, f64)>> = Vec::with_capacity(rings.len());
+    for (i, mut ring) in rings.into_iter().enumerate() {
+        let name = if i == 0 { "outer ring".to_string() } else { format!("hole {}", i) };
+        if let Some((lng, lat)) = ring.iter().find(|(lng, lat)| !valid_position(*lng, *lat)) {
+            return Err(format!("polygon {}, {}: invalid position {} {}", n, name, lng, lat));
+        }
+        if ring.len() < 4 {
+            return Err(format!("polygon {}, {}: a ring needs at least 4 positions (the last repeating \
+                the first), got {}", n, name, ring.len()));
+        }
+        if ring.first() != ring.last() {
+            return Err(format!("polygon {}, {}: ring isn't closed (the last position must repeat the \
+                first)", n, name));
+        }
+        ring.pop();
+        if distinct_positions(&ring) < 3 {
+            return Err(format!("polygon {}, {}: a ring needs at least 3 distinct positions", n, name));
+        }
+        checked.push(ring);
+    }
+
+    let outer = checked.remove(0);
+    Ok(Polygon { outer, holes: checked })
+}
+
+/// parse the polygons of a GeoJSON Polygon or MultiPolygon geometry, or of a Feature,
+/// FeatureCollection or GeometryCollection containing such geometries
+pub fn parse_geojson(json: &str) -> Result<Vec<Polygon>, String> {
+    let value: Value = serde_json::from_str(json).map_err(|err| format!("invalid GeoJSON: {}", err))?;
+    let mut polygons: Vec<Polygon> = Vec::new();
+    geojson_polygons(&value, &mut polygons)?;
+    if polygons.is_empty() {
+        return Err("the GeoJSON doesn't contain any polygons".to_string());
+    }
+    Ok(polygons)
+}
+
+fn geojson_member<'a>(value: &'a Value, name: &str) -> Result<&'a Value, String> {
+    value.get(name).ok_or_else(|| format!("invalid GeoJSON: missing \"{}\"", name))
+}
+
+fn geojson_array<'a>(value: &'a Value, what: &str) -> Result<&'a Vec<Value>, String> {
+    value.as_array().ok_or_else(|| format!("invalid GeoJSON: {} must be an array", what))
+}
+
+fn geojson_polygons(value: &Value, polygons: &mut Vec<Polygon>) -> Result<(), String> {
+    let geo_type = match geojson_member(value, "type")?.as_str() {
+        Some(geo_type) => geo_type,
+        None => return Err("invalid GeoJSON: \"type\" must be a string".to_string())
+    };
+    match geo_type {
+        "Polygon" => {
+            let coordinates = geojson_member(value, "coordinates")?;
+            let n = polygons.len() + 1;
+            polygons.push(geojson_polygon(coordinates, n)?);
+        },
+        "MultiPolygon" => {
+            let coordinates = geojson_member(value, "coordinates")?;
+            for polygon in geojson_array(coordinates, "MultiPolygon coordinates")? {
+                let n = polygons.len() + 1;
+                polygons.push(geojson_polygon(polygon, n)?);
+            }
+        },
+        "Feature" => geojson_polygons(geojson_member(value, "geometry")?, polygons)?,
+        "FeatureCollection" => {
+            for feature in geojson_array(geojson_member(value, "features")?, "features")? {
+                geojson_polygons(feature, polygons)?;
+            }
+        },
+        "GeometryCollection" => {
+            for geometry in geojson_array(geojson_member(value, "geometries")?, "geometries")? {
+                geojson_polygons(geometry, polygons)?;
+            }
+        },
+        _ => return Err(format!("unsupported GeoJSON type {} (must be Polygon or MultiPolygon)", geo_type))
+    }
+    Ok(())
+}
+
+fn geojson_polygon(coordinates: &Value, n: usize) -> Result<Polygon, String> {
+    let mut rings: Vec<Vec<(f64, f64)>> = Vec::new();
+    for ring in geojson_array(coordinates, "polygon coordinates")? {
+        let mut positions: Vec<(f64, f64)> = Vec::new();
+        for position in geojson_array(ring, "a ring")? {
+            match position.as_array().map(|position| position.as_slice()) {
+                Some([lng, lat, ..]) => match (lng.as_f64(), lat.as_f64()) {
+                    (Some(lng), Some(lat)) => positions.push((lng, lat)),
+                    _ => return Err(format!("invalid GeoJSON: polygon {} has a non-numeric position", n))
+                },
+                _ => return Err("invalid GeoJSON: positions must be [lng, lat] arrays".to_string())
+            }
+        }
+        rings.push(positions);
+    }
+    closed_rings_to_polygon(rings, n)
+}
+
+enum WktToken {
+    Open,
+    Close,
+    Comma,
+    Word(String),
+}
+
+enum WktValue {
+    List(Vec<WktValue>),
+    Position(f64, f64),
+}
+
+fn wkt_tokens(wkt: &str) -> Vec<WktToken> {
+    let mut tokens: Vec<WktToken> = Vec::new();
+    let mut word = String::new();
+    for c in wkt.chars() {
+        if c == '(' || c == ')' || c == ',' || c.is_whitespace() {
+            if !word.is_empty() {
+                tokens.push(WktToken::Word(std::mem::take(&mut word)));
+            }
+            match c {
+                '(' => tokens.push(WktToken::Open),
+                ')' => tokens.push(WktToken::Close),
+                ',' => tokens.push(WktToken::Comma),
+                _ => {}
+            }
+        } else {
+            word.push(c);
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(WktToken::Word(word));
+    }
+    tokens
+}
+
+// the deepest nesting of lists a supported WKT geometry has (MULTIPOLYGON has polygons of rings
+// of positions)
+const WKT_MAX_DEPTH: usize = 3;
+
+/// parse a parenthesized list (of lists or positions) or a position (any coordinates after
+/// lng lat, like z or m, are ignored), lists can only be nested up to depth lists deep
+fn wkt_value<'a, I: Iterator<Item = &'a WktToken>>(tokens: &mut Peekable<I>, depth: usize) -> Result<WktValue, String> {
+    if let Some(WktToken::Open) = tokens.peek() {
+        if depth == 0 {
+            return Err("invalid WKT: too many nested lists".to_string());
+        }
+        tokens.next();
+        let mut items: Vec<WktValue> = Vec::new();
+        loop {
+            items.push(wkt_value(tokens, depth - 1)?);
+            match tokens.next() {
+                Some(WktToken::Comma) => continue,
+                Some(WktToken::Close) => return Ok(WktValue::List(items)),
+                _ => return Err("invalid WKT: expected ',' or ')'".to_string())
+            }
+        }
+    }
+
+    let mut coords: Vec<f64> = Vec::new();
+    while let Some(WktToken::Word(word)) = tokens.peek() {
+        match word.parse::<f64>() {
+            Ok(coord) => coords.push(coord),
+            Err(_err) => return Err(format!("invalid WKT: {} isn't a number", word))
+        }
+        tokens.next();
+    }
+    match coords.as_slice() {
+        [lng, lat, ..] => Ok(WktValue::Position(*lng, *lat)),
+        _ => Err("invalid WKT: expected '(' or a position (lng lat)".to_string())
+    }
+}
+
+/// parse the polygons of a WKT POLYGON or MULTIPOLYGON
+pub fn parse_wkt(wkt: &str) -> Result<Vec<Polygon>, String> {
+    let tokens = wkt_tokens(wkt);
+    let mut tokens = tokens.iter().peekable();
+
+    let geo_type = match tokens.next() {
+        Some(WktToken::Word(word)) => word.to_uppercase(),
+        _ => return Err("invalid WKT: missing geometry type".to_string())
+    };
+    // skip the dimensions, the coordinates after lng lat are ignored anyway
+    while let Some(WktToken::Word(word)) = tokens.peek() {
+        match word.to_uppercase().as_str() {
+            "Z" | "M" | "ZM" => { tokens.next(); },
+            "EMPTY" => return Err("the WKT geometry is empty".to_string()),
+            _ => return Err(format!("invalid WKT: unexpected {}", word))
+        }
+    }
+
+    let value = wkt_value(&mut tokens, WKT_MAX_DEPTH)?;
+    if tokens.next().is_some() {
+        return Err("invalid WKT: unexpected text after the geometry".to_string());
+    }
+
+    match (geo_type.as_str(), value) {
+        ("POLYGON", value) => Ok(vec![wkt_polygon(value, 1)?]),
+        ("MULTIPOLYGON", WktValue::List(polygons)) => {
+            polygons.into_iter().enumerate().map(|(i, polygon)| wkt_polygon(polygon, i + 1)).collect()
+        },
+        ("MULTIPOLYGON", _) => Err("invalid WKT: MULTIPOLYGON must be a list of polygons".to_string()),
+        (geo_type, _) => Err(format!("unsupported WKT type {} (must be POLYGON or MULTIPOLYGON)", geo_type))
+    }
+}
+
+fn wkt_polygon(value: WktValue, n: usize) -> Result<Polygon, String> {
+    let not_rings = || format!("invalid WKT: polygon {} must be a list of rings of positions", n);
+    let rings = match value {
+        WktValue::List(rings) => rings,
+        WktValue::Position(..) => return Err(not_rings())
+    };
+
+    let mut polygon_rings: Vec<Vec<(f64, f64)>> = Vec::with_capacity(rings.len());
+    for ring in rings {
+        let positions = match ring {
+            WktValue::List(positions) => positions,
+            WktValue::Position(..) => return Err(not_rings())
+        };
+        let mut polygon_ring: Vec<(f64, f64)> = Vec::with_capacity(positions.len());
+        for position in positions {
+            match position {
+                WktValue::Position(lng, lat) => polygon_ring.push((lng, lat)),
+                WktValue::List(_) => return Err(not_rings())
+            }
+        }
+        polygon_rings.push(polygon_ring);
+    }
+    closed_rings_to_polygon(polygon_rings, n)
+}
@@ -12,10 +12,18 @@
 
 pub const DEG_TO_RAD: f64 = 0.017453292519943295769236907684886;
 pub const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+// no two points are further apart than half of the earth's circumference
+pub const MAX_EARTH_DISTANCE_IN_METERS: f64 = std::f64::consts::PI * EARTH_RADIUS_IN_METERS;
 
 // pub const MERCATOR_MAX: f64 = 20037726.37;
 // pub const MERCATOR_MIN: f64 = -20037726.37;
 
+// These are from redis/src/geohash.h, the limits of the positions GEOADD accepts
+pub const GEO_LAT_MIN: f64 = -85.05112878;
+pub const GEO_LAT_MAX: f64 = 85.05112878;
+pub const GEO_LONG_MIN: f64 = -180.0;
+pub const GEO_LONG_MAX: f64 = 180.0;
+
 
 pub fn deg_rad(ang: f64) -> f64 {
     ang * DEG_TO_RAD
@@ -36,3 +44,125 @@ pub fn geohash_get_distance(lon1d: f64, lat1d: f64, lon2d: f64, lat2d: f64) -> f
     return 2.0 * EARTH_RADIUS_IN_METERS *
         (u * u + lat1r.cos() * lat2r.cos() * v * v).sqrt().asin();
 }
+
+/* Calculate the north/south distance between two latitudes. */
+pub fn geohash_get_lat_distance(lat1d: f64, lat2d: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (deg_rad(lat2d) - deg_rad(lat1d)).abs()
+}
+
+/* Get the distance from the center of a width x height (in meters) box to a point if the point
+ * is inside the box, otherwise None. */
+pub fn geohash_get_distance_if_in_rectangle(width_m: f64, height_m: f64, lon1d: f64, lat1d: f64,
+                                            lon2d: f64, lat2d: f64) -> Option<f64> {
+    let lat_distance = geohash_get_lat_distance(lat2d, lat1d);
+    if lat_distance > height_m / 2.0 {
+        return None;
+    }
+    let lon_distance = geohash_get_distance(lon2d, lat2d, lon1d, lat2d);
+    if lon_distance > width_m / 2.0 {
+        return None;
+    }
+    Some(geohash_get_distance(lon1d, lat1d, lon2d, lat2d))
+}
+
+/* Check whether a point is inside a ring of lng/lat vertices (ray casting, the ring may or may
+ * not repeat its first vertex at the end). */
+pub fn ring_contains(ring: &[(f64, f64)], lon: f64, lat: f64) -> bool {
+    if ring.is_empty() {
+        return false;
+    }
+    let mut inside = false;
+    let mut j = ring.len() - 1;
+    for i in 0..ring.len() {
+        let (lon_i, lat_i) = ring[i];
+        let (lon_j, lat_j) = ring[j];
+        if (lat_i > lat) != (lat_j > lat) &&
+            lon < (lon_j - lon_i) * (lat - lat_i) / (lat_j - lat_i) + lon_i {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+// A polygon made up of an outer ring and zero or more holes, as lng/lat vertices
+#[derive(Clone, Debug, PartialEq)]
+pub struct Polygon {
+    pub outer: Vec<(f64, f64)>,
+    pub holes: Vec<Vec<(f64, f64)>>,
+}
+
+impl Polygon {
+    pub fn contains(&self, lon: f64, lat: f64) -> bool {
+        ring_contains(&self.outer, lon, lat) &&
+            !self.holes.iter().any(|hole| ring_contains(hole, lon, lat))
+    }
+
+    /* Approximate width and height of the polygon's bounding box in meters. */
+    pub fn bbox_size(&self) -> (f64, f64) {
+        let lons = self.outer.iter().map(|(lon, _)| *lon);
+        let lats = self.outer.iter().map(|(_, lat)| *lat);
+        let (min_lon, max_lon) = lons.fold((f64::MAX, f64::MIN), |(min, max), v| (min.min(v), max.max(v)));
+        let (min_lat, max_lat) = lats.fold((f64::MAX, f64::MIN), |(min, max), v| (min.min(v), max.max(v)));
+        let mid_lat = (min_lat + max_lat) / 2.0;
+        let width = geohash_get_distance(min_lon, mid_lat, max_lon, mid_lat);
+        let height = geohash_get_lat_distance(min_lat, max_lat);
+        (width, height)
+    }
+}
+
+/* Format a ring of lng/lat vertices as the coordinates of a GeoJSON polygon ring (closed, as
+ * GeoJSON requires). */
+fn geojson_ring(ring: &[(f64, f64)]) -> String {
+    let mut coords: Vec<String> = ring.iter().map(|(lon, lat)| format!("[{},{}]", lon, lat)).collect();
+    if let Some(first) = coords.first().cloned() {
+        if ring.first() != ring.last() {
+            coords.push(first);
+        }
+    }
+    format!("[{}]", coords.join(","))
+}
+
+/* Format a ring of lng/lat vertices as a WKT polygon ring (closed, as WKT requires). */
+fn wkt_ring(ring: &[(f64, f64)]) -> String {
+    let mut coords: Vec<String> = ring.iter().map(|(lon, lat)| format!("{} {}", lon, lat)).collect();
+    if let Some(first) = coords.first().cloned() {
+        if ring.first() != ring.last() {
+            coords.push(first);
+        }
+    }
+    format!("({})", coords.join(", "))
+}
+
+/* Format a ring of lng/lat vertices as a GeoJSON Polygon geometry. */
+pub fn ring_to_geojson(ring: &[(f64, f64)]) -> String {
+    format!("{{\"type\":\"Polygon\",\"coordinates\":[{}]}}", geojson_ring(ring))
+}
+
+/* Format a ring of lng/lat vertices as a WKT POLYGON. */
+pub fn ring_to_wkt(ring: &[(f64, f64)]) -> String {
+    format!("POLYGON({})", wkt_ring(ring))
+}
+
+/* Format a string as a JSON string (quoted and escaped). */
+pub fn json_string(s: &str) -> String {
+    serde_json::Value::from(s).to_string()
+}
+
+/* Format a lng/lat position as a GeoJSON Point geometry. */
+pub fn point_to_geojson(lon: f64, lat: f64) -> String {
+    format!("{{\"type\":\"Point\",\"coordinates\":[{},{}]}}", lon, lat)
+}
+
+/* Format a GeoJSON Feature from a geometry and (name, JSON value) properties. */
+pub fn geojson_feature(geometry: &str, properties: &[(&str, String)]) -> String {
+    let properties: Vec<String> = properties.iter()
+        .map(|(name, value)| format!("{}:{}", json_string(name), value))
+        .collect();
+    format!("{{\"type\":\"Feature\",\"geometry\":{},\"properties\":{{{}}}}}", geometry, properties.join(","))
+}
+
+/* Format a GeoJSON FeatureCollection of features. */
+pub fn geojson_feature_collection(features: &[String]) -> String {
+    format!("{{\"type\":\"FeatureCollection\",\"features\":[{}]}}", features.join(","))
+}
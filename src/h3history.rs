@@ -0,0 +1,181 @@
+// The h3history native data type, the last-updated times and bounded position histories of the
+// members of an H3 key, kept in a single key per H3 key instead of a key per member
+
+use std::collections::{HashMap, VecDeque};
+use std::mem::size_of;
+use std::os::raw::{c_int, c_void};
+use std::ptr::null_mut;
+
+use redis_module::native_types::RedisType;
+use redis_module::raw as rawmod;
+
+use crate::nativeutil::emit_aof_vector;
+
+const H3HISTORY_ENCODING_VERSION: c_int = 0;
+
+pub static H3HISTORY_TYPE: RedisType = RedisType::new(
+    "h3history",
+    H3HISTORY_ENCODING_VERSION,
+    rawmod::RedisModuleTypeMethods {
+        version: rawmod::REDISMODULE_TYPE_METHOD_VERSION as u64,
+        rdb_load: Some(rdb_load),
+        rdb_save: Some(rdb_save),
+        aof_rewrite: Some(aof_rewrite),
+        mem_usage: Some(mem_usage),
+        digest: Some(digest),
+        free: Some(free),
+        aux_load: None,
+        aux_save: None,
+        aux_save_triggers: 0,
+        free_effort: None,
+        unlink: None,
+        copy: None,
+        defrag: None,
+    },
+);
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MemberHistory {
+    // the latest time (in ms since the epoch) the member was updated at
+    pub updated: u64,
+    // (ts, H3Index as u64) positions, oldest first
+    pub positions: VecDeque<(u64, u64)>,
+}
+
+pub struct PositionHistory {
+    members: HashMap<String, MemberHistory>,
+}
+
+impl PositionHistory {
+    pub fn new() -> PositionHistory {
+        PositionHistory {
+            members: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// record that member was at h3ll at time ts, keeping at most max_positions of its positions
+    /// (with 0 only its last-updated time is recorded and its history is left as is)
+    pub fn record(&mut self, member: &str, h3ll: u64, ts: u64, max_positions: usize) {
+        let history = self.members.entry(member.to_string()).or_insert_with(MemberHistory::default);
+        history.updated = history.updated.max(ts);
+        if max_positions == 0 {
+            return;
+        }
+        // positions can be recorded out of order (see H3.ADD TS), they are kept in time order
+        let at = history.positions.partition_point(|(pos_ts, _h3ll)| *pos_ts <= ts);
+        history.positions.insert(at, (ts, h3ll));
+        while history.positions.len() > max_positions {
+            history.positions.pop_front();
+        }
+    }
+
+    /// restore member's last-updated time and add positions to its history (see aof_rewrite),
+    /// keeping at most max_positions of them (with 0 all of them are kept, as when loading)
+    pub fn restore(&mut self, member: &str, updated: u64, positions: &[(u64, u64)], max_positions: usize) {
+        let history = self.members.entry(member.to_string()).or_insert_with(MemberHistory::default);
+        history.updated = history.updated.max(updated);
+        for (ts, h3ll) in positions {
+            let at = history.positions.partition_point(|(pos_ts, _h3ll)| pos_ts <= ts);
+            history.positions.insert(at, (*ts, *h3ll));
+        }
+        while max_positions > 0 && history.positions.len() > max_positions {
+            history.positions.pop_front();
+        }
+    }
+
+    /// remove member's last-updated time and history, returns whether it had any
+    pub fn remove(&mut self, member: &str) -> bool {
+        self.members.remove(member).is_some()
+    }
+
+    pub fn get(&self, member: &str) -> Option<&MemberHistory> {
+        self.members.get(member)
+    }
+
+    /// iterate over members with their histories, in member order
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &MemberHistory)> + '_ {
+        let mut members: Vec<(&String, &MemberHistory)> = self.members.iter().collect();
+        members.sort_by(|(a, _), (b, _)| a.cmp(b));
+        members.into_iter()
+    }
+}
+
+unsafe extern "C" fn rdb_load(rdb: *mut rawmod::RedisModuleIO, encver: c_int) -> *mut c_void {
+    if encver != H3HISTORY_ENCODING_VERSION {
+        return null_mut();
+    }
+
+    let mut history = PositionHistory::new();
+    let len = rawmod::load_unsigned(rdb);
+    for _ in 0..len {
+        let member = rawmod::load_string(rdb);
+        let updated = rawmod::load_unsigned(rdb);
+        let positions_len = rawmod::load_unsigned(rdb);
+        let positions: Vec<(u64, u64)> = (0..positions_len)
+            .map(|_| (rawmod::load_unsigned(rdb), rawmod::load_unsigned(rdb)))
+            .collect();
+        history.restore(&member, updated, &positions, 0);
+    }
+    Box::into_raw(Box::new(history)) as *mut c_void
+}
+
+unsafe extern "C" fn rdb_save(rdb: *mut rawmod::RedisModuleIO, value: *mut c_void) {
+    let history = &*(value as *mut PositionHistory);
+    rawmod::save_unsigned(rdb, history.len() as u64);
+    for (member, member_history) in history.iter() {
+        rawmod::save_string(rdb, member);
+        rawmod::save_unsigned(rdb, member_history.updated);
+        rawmod::save_unsigned(rdb, member_history.positions.len() as u64);
+        for (ts, h3ll) in &member_history.positions {
+            rawmod::save_unsigned(rdb, *ts);
+            rawmod::save_unsigned(rdb, *h3ll);
+        }
+    }
+}
+
+unsafe extern "C" fn aof_rewrite(aof: *mut rawmod::RedisModuleIO, key: *mut rawmod::RedisModuleString,
+                                 value: *mut c_void) {
+    let history = &*(value as *mut PositionHistory);
+    // one H3.HISTORYADD historykey member updated [ts h3idx ...] per member
+    for (member, member_history) in history.iter() {
+        let mut args: Vec<String> = Vec::with_capacity(2 + 2 * member_history.positions.len());
+        args.push(member.clone());
+        args.push(member_history.updated.to_string());
+        for (ts, h3ll) in &member_history.positions {
+            args.push(ts.to_string());
+            args.push(format!("{:x}", h3ll));
+        }
+        emit_aof_vector(aof, "H3.HISTORYADD", key, &args);
+    }
+}
+
+unsafe extern "C" fn mem_usage(value: *const c_void) -> usize {
+    let history = &*(value as *const PositionHistory);
+    let mut size = size_of::<PositionHistory>();
+    for (member, member_history) in history.members.iter() {
+        size += size_of::<String>() + member.len() + size_of::<MemberHistory>();
+        size += member_history.positions.capacity() * size_of::<(u64, u64)>();
+    }
+    size
+}
+
+unsafe extern "C" fn digest(md: *mut rawmod::RedisModuleDigest, value: *mut c_void) {
+    let history = &*(value as *mut PositionHistory);
+    for (member, member_history) in history.iter() {
+        rawmod::RedisModule_DigestAddStringBuffer.unwrap()(md, member.as_ptr() as *mut _, member.len());
+        rawmod::RedisModule_DigestAddLongLong.unwrap()(md, member_history.updated as i64);
+        for (ts, h3ll) in &member_history.positions {
+            rawmod::RedisModule_DigestAddLongLong.unwrap()(md, *ts as i64);
+            rawmod::RedisModule_DigestAddLongLong.unwrap()(md, *h3ll as i64);
+        }
+        rawmod::RedisModule_DigestEndSequence.unwrap()(md);
+    }
+}
+
+unsafe extern "C" fn free(value: *mut c_void) {
+    drop(Box::from_raw(value as *mut PositionHistory));
+}
@@ -0,0 +1,151 @@
+// The h3idx-set native data type, a set of members keyed by their full (any resolution) H3
+// indices, as an alternative to storing H3 indices as zset scores
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::mem::size_of;
+use std::os::raw::{c_int, c_void};
+use std::ptr::null_mut;
+
+use redis_module::native_types::RedisType;
+use redis_module::raw as rawmod;
+
+use crate::h3util::{index_descendant_range, index_sort_key};
+use crate::nativeutil::emit_aof_vector;
+
+const H3SET_ENCODING_VERSION: c_int = 0;
+
+pub static H3SET_TYPE: RedisType = RedisType::new(
+    "h3idx-set",
+    H3SET_ENCODING_VERSION,
+    rawmod::RedisModuleTypeMethods {
+        version: rawmod::REDISMODULE_TYPE_METHOD_VERSION as u64,
+        rdb_load: Some(rdb_load),
+        rdb_save: Some(rdb_save),
+        aof_rewrite: Some(aof_rewrite),
+        mem_usage: Some(mem_usage),
+        digest: Some(digest),
+        free: Some(free),
+        aux_load: None,
+        aux_save: None,
+        aux_save_triggers: 0,
+        free_effort: None,
+        unlink: None,
+        copy: None,
+        defrag: None,
+    },
+);
+
+pub struct H3Set {
+    // sort key (see index_sort_key) -> members with that index
+    cells: BTreeMap<u64, BTreeSet<String>>,
+    // member -> H3Index as u64
+    members: HashMap<String, u64>,
+}
+
+impl H3Set {
+    pub fn new() -> H3Set {
+        H3Set {
+            cells: BTreeMap::new(),
+            members: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// add or move member to h3ll, returns true if member is new
+    pub fn insert(&mut self, member: String, h3ll: u64) -> bool {
+        let is_new = self.remove(&member).is_none();
+        self.cells.entry(index_sort_key(h3ll)).or_insert_with(BTreeSet::new).insert(member.clone());
+        self.members.insert(member, h3ll);
+        is_new
+    }
+
+    /// remove member, returns its H3Index as u64 if it was in the set
+    pub fn remove(&mut self, member: &str) -> Option<u64> {
+        let h3ll = self.members.remove(member)?;
+        let sort_key = index_sort_key(h3ll);
+        if let Some(cell_members) = self.cells.get_mut(&sort_key) {
+            cell_members.remove(member);
+            if cell_members.is_empty() {
+                self.cells.remove(&sort_key);
+            }
+        }
+        Some(h3ll)
+    }
+
+    pub fn get(&self, member: &str) -> Option<u64> {
+        self.members.get(member).copied()
+    }
+
+    /// get the (member, H3Index as u64) pairs whose cells are the cell of h3ll or any of its
+    /// descendants, in index order
+    pub fn cell_members(&self, h3ll: u64) -> Vec<(String, u64)> {
+        let (min, max) = index_descendant_range(h3ll);
+        self.cells.range(min..=max)
+            .flat_map(|(_, cell_members)| cell_members.iter())
+            .map(|member| (member.clone(), self.members[member]))
+            .collect()
+    }
+
+    /// iterate over (member, H3Index as u64) pairs in index order
+    pub fn iter(&self) -> impl Iterator<Item = (&String, u64)> + '_ {
+        self.cells.values()
+            .flat_map(|cell_members| cell_members.iter())
+            .map(move |member| (member, self.members[member]))
+    }
+}
+
+unsafe extern "C" fn rdb_load(rdb: *mut rawmod::RedisModuleIO, encver: c_int) -> *mut c_void {
+    if encver != H3SET_ENCODING_VERSION {
+        return null_mut();
+    }
+
+    let mut set = H3Set::new();
+    let len = rawmod::load_unsigned(rdb);
+    for _ in 0..len {
+        let h3ll = rawmod::load_unsigned(rdb);
+        let member = rawmod::load_string(rdb);
+        set.insert(member, h3ll);
+    }
+    Box::into_raw(Box::new(set)) as *mut c_void
+}
+
+unsafe extern "C" fn rdb_save(rdb: *mut rawmod::RedisModuleIO, value: *mut c_void) {
+    let set = &*(value as *mut H3Set);
+    rawmod::save_unsigned(rdb, set.len() as u64);
+    for (member, h3ll) in set.iter() {
+        rawmod::save_unsigned(rdb, h3ll);
+        rawmod::save_string(rdb, member);
+    }
+}
+
+unsafe extern "C" fn aof_rewrite(aof: *mut rawmod::RedisModuleIO, key: *mut rawmod::RedisModuleString,
+                                 value: *mut c_void) {
+    let set = &*(value as *mut H3Set);
+    for (member, h3ll) in set.iter() {
+        emit_aof_vector(aof, "H3.SETADD", key, &[format!("{:x}", h3ll), member.clone()]);
+    }
+}
+
+unsafe extern "C" fn mem_usage(value: *const c_void) -> usize {
+    let set = &*(value as *const H3Set);
+    // each member is stored in both maps along with its index
+    let per_member = 2 * size_of::<String>() + 2 * size_of::<u64>();
+    let names: usize = set.members.keys().map(|member| 2 * member.len()).sum();
+    size_of::<H3Set>() + set.len() * per_member + names
+}
+
+unsafe extern "C" fn digest(md: *mut rawmod::RedisModuleDigest, value: *mut c_void) {
+    let set = &*(value as *mut H3Set);
+    for (member, h3ll) in set.iter() {
+        rawmod::RedisModule_DigestAddLongLong.unwrap()(md, h3ll as i64);
+        rawmod::RedisModule_DigestAddStringBuffer.unwrap()(md, member.as_ptr() as *mut _, member.len());
+        rawmod::RedisModule_DigestEndSequence.unwrap()(md);
+    }
+}
+
+unsafe extern "C" fn free(value: *mut c_void) {
+    drop(Box::from_raw(value as *mut H3Set));
+}
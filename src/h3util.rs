@@ -1,6 +1,10 @@
-use h3_rs::{Error as H3Error, GeoCoord, H3Index};
+use std::collections::{HashMap, HashSet};
+
+use h3_rs::{Error as H3Error, GeoCoord, GeoPolygon, H3Index};
 use regex::Regex;
 
+use crate::geoutil::{DEG_TO_RAD, EARTH_RADIUS_IN_METERS, MAX_EARTH_DISTANCE_IN_METERS, Polygon};
+
 // H3 indices used as scores must have max resolution
 pub const MIN_RESOLUTION: i32 = 0;
 pub const MAX_RESOLUTION: i32 = 15;
@@ -26,19 +30,51 @@ const H3_RES_MASK_NEGATIVE: u64 = !H3_RES_MASK;
 //    hex: 8001fffffffffff
 //    bin: 0b0000_1000_0000_0000_0001_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111
 
+// base cell bits follow the resolution bits, then the 45 bits of child digits
+const H3_BC_OFFSET: u64 = 45;
+const H3_BC_MASK: u64 = 127 << H3_BC_OFFSET;
+
+// the 12 base cells that are pentagons
+const PENTAGON_BASE_CELLS: [u64; 12] = [4, 14, 24, 38, 49, 58, 63, 72, 83, 97, 107, 117];
+
 // masks out the top 12 bits
 // dec: 4503599627370495
 // hex: 0x000FFFFFFFFFFFFF
 // bin: 0b0000_0000_0000_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111_1111
 const LOW52_MASK: u64 = 0x000FFFFFFFFFFFFF;
 
-// assumes mode == 1 and resolution == 15
-// >>52 to get resolution
-// dec: 644014746713980928
-// hex: 0x08F0000000000000
-// bin: 0b0000_1000_1111_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000
-const HIGH12_BITS: u64 = 0x08F0000000000000;
+// assumes mode == 1, the resolution is set separately
+// dec: 576460752303423488
+// hex: 0x0800000000000000
+// bin: 0b0000_1000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000
+const HIGH12_BITS: u64 = 0x0800000000000000;
+
 
+// average hexagon edge length in meters for each resolution, see:
+//       https://h3geo.org/docs/core-library/restable
+const EDGE_LENGTH_M: [f64; 16] = [
+    1107712.591, 418676.0055, 158244.6558, 59810.85794,
+    22606.3794, 8544.408276, 3229.482772, 1220.629759,
+    461.3546837, 174.3756681, 65.90780749, 24.9105614,
+    9.415526211, 3.559893033, 1.348574562, 0.509713273,
+];
+
+// average hexagon area in square meters for each resolution (same source as above)
+const CELL_AREA_M2: [f64; 16] = [
+    4250546848000.0, 607220978200.0, 86745854040.0, 12392264860.0,
+    1770323552.0, 252903364.5, 36129052.1, 5161293.2,
+    737327.6, 105332.5, 15047.5, 2149.6,
+    307.1, 43.9, 6.3, 0.9,
+];
+
+// the max number of cells a polygon should be filled with when no resolution is given
+const MAX_POLYFILL_CELLS: f64 = 10000.0;
+// the max number of cells a polygon can be filled with at a given resolution
+pub const MAX_RES_POLYFILL_CELLS: f64 = 100000.0;
+
+// actual edge lengths can be noticeably smaller than the average depending on where a cell
+// is on the icosahedron, so radius calculations assume this fraction of the average
+const MIN_EDGE_RATIO: f64 = 0.7;
 
 // A discussion of why double is used instead of long long for zset scores:
 // https://github.com/antirez/redis/issues/6209
@@ -47,7 +83,9 @@ const HIGH12_BITS: u64 = 0x08F0000000000000;
 //       1) reserved bit == 0      1 bit
 //       2) index mode == 1        4 bits
 //       3) reserved bits == 0     3 bits
-//       4) cell resolution == 15  4 bits
+//       4) cell resolution        4 bits
+//       the resolution doesn't need to be stored because the unused child digits of a cell
+//       are set to 7, so it is the number of child digits before the first 7
 // see:
 //       https://h3geo.org/docs/core-library/h3indexing
 
@@ -59,12 +97,28 @@ pub fn h3ll_to_score(mut h3ll: u64) -> f64 {
 
 // convert zset score double to H3 long long
 pub fn score_to_h3ll(score: f64) -> u64 {
-    // dec: 644014746713980928
-    // hex: 0x08F0000000000000
-    // bin: 0b0000_1000_1111_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000
     let mut h3ll = score as u64;
     h3ll |= HIGH12_BITS; // Set high bits
-    h3ll
+    set_resolution(h3ll, low52_resolution(h3ll))
+}
+
+// get the resolution from the child digits of an H3 long long (see NOTE above)
+fn low52_resolution(h3ll: u64) -> u8 {
+    let mut res: u8 = 0;
+    while res < 15 && (h3ll >> (3 * (14 - res) as u64)) & 7 != 7 {
+        res += 1;
+    }
+    res
+}
+
+// get the u64 value of an H3Index (the member is not pub, so go through the hex string)
+pub fn h3_to_h3ll(h3idx: &H3Index) -> u64 {
+    u64::from_str_radix(h3idx.to_string().as_str(), 16).unwrap()
+}
+
+// get the vertices of the boundary of an H3Index's cell as lng/lat pairs
+pub fn cell_boundary(h3idx: &H3Index) -> Vec<(f64, f64)> {
+    h3idx.to_geo_boundary().iter().map(|coord| (coord.lon, coord.lat)).collect()
 }
 
 // convert string to H3Index, string can be either a valid hex key or long long value
@@ -101,6 +155,38 @@ fn set_resolution(h3ll: u64, new_res: u8) -> u64 {
     (h3ll & H3_RES_MASK_NEGATIVE) | (new_res as u64) << H3_RES_OFFSET
 }
 
+/// for a given H3Index as u64 (any resolution), get a key that orders indices the same way as
+/// zset scores do, so that a cell sorts right after all of its descendants (unused child digits
+/// are 7, so the resolution can still be determined from the key)
+pub fn index_sort_key(h3ll: u64) -> u64 {
+    h3ll & H3_RES_MASK_NEGATIVE
+}
+
+/// for a given H3Index as u64, get the range of sort keys of the cell and all its descendants
+/// at any resolution
+pub fn index_descendant_range(h3ll: u64) -> (u64, u64) {
+    (index_sort_key(index_min_child(h3ll)), index_sort_key(h3ll))
+}
+
+/// for a given H3Index as u64, get its ancestor cells at all lower resolutions, from the
+/// finest to the coarsest
+pub fn index_ancestors(h3ll: u64) -> Vec<u64> {
+    (0..get_resolution(h3ll)).rev().map(|res| index_parent(h3ll, res)).collect()
+}
+
+/// for a given H3Index as u64, get the parent cell at resolution res (if res is not lower than
+/// the index's resolution, the index itself is returned)
+pub fn index_parent(h3ll: u64, res: u8) -> u64 {
+    if res >= get_resolution(h3ll) {
+        return h3ll;
+    }
+
+    // unused child digits are set to 7 (all bits set)
+    let unused_bit_length = (15 - res) * 3;
+    let unused_bits: u64 = (1 << (unused_bit_length as u64)) - 1;
+    set_resolution(h3ll, res) | unused_bits
+}
+
 /// for a given H3Index as u64, get the lowest valued res 15 child cell
 pub fn index_min_child(h3ll: u64) -> u64 {
     let res = get_resolution(h3ll);
@@ -119,31 +205,226 @@ pub fn index_min_child(h3ll: u64) -> u64 {
     min_child
 }
 
-/// for a given H3Index as u64, get the highest valued res 15 child cell
-pub fn index_max_child(h3ll: u64) -> u64 {
+/// for a given radius in meters, get a resolution and k value such that the k-ring around the
+/// cell containing any point is guaranteed to cover a circle of that radius around the point
+///
+/// The resolution is the finest one whose edge length is at least half the radius, so that
+/// searches only need to query a small number of cells. Radii are capped at the largest distance
+/// between two points, so k stays small (about 15 at res 0, which covers the whole earth).
+pub fn radius_to_kring(radius: f64) -> (i32, i32) {
+    let radius = if radius.is_finite() { radius.min(MAX_EARTH_DISTANCE_IN_METERS) } else { MAX_EARTH_DISTANCE_IN_METERS };
+    let mut res = MAX_RESOLUTION;
+    while res > MIN_RESOLUTION && EDGE_LENGTH_M[res as usize] < radius / 2.0 {
+        res -= 1;
+    }
+
+    // a k-ring covers at least ((k + 0.5) * sqrt(3) - 1) edge lengths from any point in the
+    // center cell, solve for k
+    let min_edge = EDGE_LENGTH_M[res as usize] * MIN_EDGE_RATIO;
+    let k = ((radius / min_edge + 1.0) / 3f64.sqrt() - 0.5).ceil();
+    (res, k.max(1.0) as i32)
+}
+
+/// for a given resolution and k, get the radius in meters that the k-ring around the cell
+/// containing any point is guaranteed to cover around the point (the inverse of radius_to_kring)
+pub fn kring_covered_radius(res: i32, k: i32) -> f64 {
+    let min_edge = EDGE_LENGTH_M[res as usize] * MIN_EDGE_RATIO;
+    (((k as f64 + 0.5) * 3f64.sqrt() - 1.0) * min_edge).max(0.0)
+}
+
+/// estimate the number of cells at resolution res that polygons with the given bounding box sizes
+/// (width and height in meters) are filled with, including the cells along their borders, for
+/// which each side of a bounding box gets an extra edge length (so that a polygon without any
+/// area still needs cells along its length)
+pub fn polyfill_cell_estimate(bbox_sizes: &[(f64, f64)], res: i32) -> f64 {
+    let edge = EDGE_LENGTH_M[res as usize];
+    let area: f64 = bbox_sizes.iter()
+        .map(|(width, height)| (width + 2.0 * edge) * (height + 2.0 * edge))
+        .sum();
+    area / CELL_AREA_M2[res as usize]
+}
+
+/// get the finest resolution at which polygons with the given bounding box sizes (see
+/// polyfill_cell_estimate) can be filled with a reasonable number of cells
+pub fn polyfill_resolution(bbox_sizes: &[(f64, f64)]) -> i32 {
+    let mut res = MAX_RESOLUTION;
+    while res > MIN_RESOLUTION && polyfill_cell_estimate(bbox_sizes, res) > MAX_POLYFILL_CELLS {
+        res -= 1;
+    }
+    res
+}
+
+/// get the cells at resolution res whose centroids are inside polygon
+pub fn polyfill(polygon: &Polygon, res: i32) -> Vec<H3Index> {
+    let to_geo_coords = |ring: &Vec<(f64, f64)>| -> Vec<GeoCoord> {
+        ring.iter().map(|(lng, lat)| GeoCoord::new(*lat, *lng)).collect()
+    };
+    let geo_polygon = GeoPolygon::new(
+        to_geo_coords(&polygon.outer),
+        polygon.holes.iter().map(to_geo_coords).collect(),
+    );
+    geo_polygon.polyfill(res)
+}
+
+/// get the cells at resolution res that the edges of a ring of lng/lat vertices pass through
+/// (straight lines in lng/lat, like ring_contains assumes), the edges are sampled at less than
+/// half the edge length of a cell, so any cell an edge crosses is one of the cells found or one
+/// of their neighbors
+pub fn ring_cells(ring: &[(f64, f64)], res: i32) -> Result<Vec<H3Index>, H3Error> {
+    let spacing = EDGE_LENGTH_M[res as usize] * MIN_EDGE_RATIO / 2.0;
+    let mut cells: Vec<H3Index> = Vec::new();
+    let mut last: Option<u64> = None;
+    for i in 0..ring.len() {
+        let (lng1, lat1) = ring[i];
+        let (lng2, lat2) = ring[(i + 1) % ring.len()];
+        // an upper bound of the edge's length, as if a degree of longitude was as long as a degree
+        // of latitude everywhere
+        let length = (lng2 - lng1).hypot(lat2 - lat1) * DEG_TO_RAD * EARTH_RADIUS_IN_METERS;
+        let steps = (length / spacing).ceil().max(1.0) as usize;
+        for step in 0..steps {
+            let t = step as f64 / steps as f64;
+            let h3idx = GeoCoord::new(lat1 + t * (lat2 - lat1), lng1 + t * (lng2 - lng1)).to_h3(res)?;
+            let h3ll = h3_to_h3ll(&h3idx);
+            if last != Some(h3ll) {
+                last = Some(h3ll);
+                cells.push(h3idx);
+            }
+        }
+    }
+    Ok(cells)
+}
+
+/// for a list of H3Index values as u64 (any resolution), get the minimal sorted list of
+/// (min, max) sort key ranges (see index_sort_key) that cover all of them and their descendants,
+/// merging overlapping ranges and ranges that are next to each other in sort key order (the
+/// ranges of consecutive sibling cells are, but the last child of a cell and the first child of
+/// the next cell aren't, as the first cell's sort key is between them)
+pub fn merge_cell_ranges(h3lls: &[u64]) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = h3lls.iter()
+        .map(|h3ll| index_descendant_range(*h3ll))
+        .collect();
+    ranges.sort();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (min, max) in ranges {
+        match merged.last_mut() {
+            Some(last) if min <= last.1 + 1 => {
+                if max > last.1 {
+                    last.1 = max;
+                }
+            },
+            _ => merged.push((min, max)),
+        }
+    }
+    merged
+}
+
+pub fn index_resolution(h3ll: u64) -> u8 {
+    get_resolution(h3ll)
+}
+
+pub fn index_base_cell(h3ll: u64) -> u64 {
+    (h3ll & H3_BC_MASK) >> H3_BC_OFFSET
+}
+
+/// a cell is a pentagon if its base cell is a pentagon and all of its child digits are 0
+pub fn index_is_pentagon(h3ll: u64) -> bool {
     let res = get_resolution(h3ll);
-    // if res == 15 it's already the max (has no children)
-    if res == 15 {
+    let unused_bits: u64 = (1 << (3 * (15 - res) as u64)) - 1;
+    let digit_bits: u64 = ((1 << H3_BC_OFFSET) - 1) & !unused_bits;
+    PENTAGON_BASE_CELLS.contains(&index_base_cell(h3ll)) && h3ll & digit_bits == 0
+}
+
+/// for a given H3Index as u64, get its center child cell at resolution res (if res is not
+/// higher than the index's resolution, the index itself is returned)
+pub fn index_center_child(h3ll: u64, res: u8) -> u64 {
+    let cur_res = get_resolution(h3ll);
+    if res <= cur_res {
         return h3ll;
     }
 
-    let mut max_child: u64 = set_resolution(h3ll, 15);
+    // zero out the child digits from cur_res + 1 to res, the digits after res stay unused (7)
+    let zero_bits: u64 = ((1 << (3 * (res - cur_res) as u64)) - 1) << (3 * (15 - res) as u64);
+    set_resolution(h3ll, res) & !zero_bits
+}
 
-    // shift down and back up to zero-out the child index bits
-    let child_index_bit_length = (15 - res) * 3;
-    max_child = max_child >> (child_index_bit_length as u64);
-    max_child = max_child << (child_index_bit_length as u64);
-
-    // set all child cell indices to highest (6)
-    let mut child_index_bits: u64 = 0;
-    let mut next_res = res + 1;
-    while next_res <= 15 {
-        let diff = 15 - next_res;
-        child_index_bits += 6 << (3 * diff) as u64;
-        next_res += 1;
+/// for a given H3Index as u64, get the number of its children at resolution res
+pub fn index_children_count(h3ll: u64, res: u8) -> u64 {
+    let cur_res = get_resolution(h3ll);
+    if res <= cur_res {
+        return 1;
+    }
+    let n = 7u64.pow((res - cur_res) as u32);
+    // pentagons have one fewer child at each resolution, except for the center child
+    if index_is_pentagon(h3ll) { 1 + 5 * (n - 1) / 6 } else { n }
+}
+
+/// for a given H3Index as u64, get (up to limit of) its children at resolution res, in index order
+pub fn index_children(h3ll: u64, res: u8, limit: usize) -> Vec<u64> {
+    let cur_res = get_resolution(h3ll);
+    if res <= cur_res {
+        return vec![h3ll];
     }
 
-    max_child |= child_index_bits;
-    max_child
+    let depth = (res - cur_res) as u32;
+    let pentagon = index_is_pentagon(h3ll);
+    let center_child = index_center_child(h3ll, res);
+
+    let mut children: Vec<u64> = Vec::new();
+    let mut n: u64 = 0;
+    while n < 7u64.pow(depth) && children.len() < limit {
+        // the digits of n in base 7 are the child digits from cur_res + 1 to res
+        let mut child = center_child;
+        let mut rest = n;
+        let mut leading_digit = 0;
+        for i in 0..depth {
+            let digit = rest % 7;
+            child |= digit << (3 * (15 - res as u64 + i as u64));
+            if digit != 0 {
+                leading_digit = digit;
+            }
+            rest /= 7;
+        }
+        // the children of a pentagon whose first non-zero digit is 1 (the deleted K axis)
+        // don't exist
+        if !(pentagon && leading_digit == 1) {
+            children.push(child);
+        }
+        n += 1;
+    }
+    children
 }
 
+/// compact a set of H3 indices as u64 (any resolutions), dropping cells that are contained in
+/// other cells of the set and replacing every complete set of siblings with their parent, the
+/// result is in index order
+pub fn compact_cells(h3lls: &[u64]) -> Vec<u64> {
+    let mut cells: HashSet<u64> = h3lls.iter().copied().collect();
+
+    let all: Vec<u64> = cells.iter().copied().collect();
+    for h3ll in all {
+        if index_ancestors(h3ll).iter().any(|ancestor| cells.contains(ancestor)) {
+            cells.remove(&h3ll);
+        }
+    }
+
+    // from the finest resolution up, so that new parents can be compacted further
+    for res in (MIN_RESOLUTION as u8 + 1..=MAX_RESOLUTION as u8).rev() {
+        let mut siblings: HashMap<u64, u64> = HashMap::new();
+        for h3ll in cells.iter().filter(|h3ll| get_resolution(**h3ll) == res) {
+            *siblings.entry(index_parent(*h3ll, res - 1)).or_insert(0) += 1;
+        }
+        for (parent, count) in siblings {
+            if count == index_children_count(parent, res) {
+                for child in index_children(parent, res, count as usize) {
+                    cells.remove(&child);
+                }
+                cells.insert(parent);
+            }
+        }
+    }
+
+    let mut compacted: Vec<u64> = cells.into_iter().collect();
+    compacted.sort_by_key(|h3ll| index_sort_key(*h3ll));
+    compacted
+}
@@ -0,0 +1,179 @@
+// The h3zoneidx native data type, a reverse geofence index that maps cells to the ids of the
+// zones (polygons or cell sets) that contain them, so that the zones containing a point can be
+// found by looking up the point's cell and its ancestors
+
+use std::collections::{BTreeSet, HashMap};
+use std::iter::once;
+use std::mem::size_of;
+use std::os::raw::{c_int, c_void};
+use std::ptr::null_mut;
+
+use redis_module::native_types::RedisType;
+use redis_module::raw as rawmod;
+
+use crate::h3util::{compact_cells, index_ancestors};
+use crate::nativeutil::emit_aof_vector;
+
+const H3ZONES_ENCODING_VERSION: c_int = 0;
+
+pub static H3ZONES_TYPE: RedisType = RedisType::new(
+    "h3zoneidx",
+    H3ZONES_ENCODING_VERSION,
+    rawmod::RedisModuleTypeMethods {
+        version: rawmod::REDISMODULE_TYPE_METHOD_VERSION as u64,
+        rdb_load: Some(rdb_load),
+        rdb_save: Some(rdb_save),
+        aof_rewrite: Some(aof_rewrite),
+        mem_usage: Some(mem_usage),
+        digest: Some(digest),
+        free: Some(free),
+        aux_load: None,
+        aux_save: None,
+        aux_save_triggers: 0,
+        free_effort: None,
+        unlink: None,
+        copy: None,
+        defrag: None,
+    },
+);
+
+pub struct ZoneIndex {
+    // cell (H3Index as u64) -> ids of the zones that contain it
+    cells: HashMap<u64, BTreeSet<String>>,
+    // zone id -> its compacted cells
+    zones: HashMap<String, BTreeSet<u64>>,
+}
+
+impl ZoneIndex {
+    pub fn new() -> ZoneIndex {
+        ZoneIndex {
+            cells: HashMap::new(),
+            zones: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.zones.len()
+    }
+
+    /// add cells (any resolutions) to zone, the zone's cells are kept compacted, returns the
+    /// number of cells the zone has
+    pub fn add(&mut self, zone: &str, h3lls: &[u64]) -> usize {
+        let mut cells: Vec<u64> = match self.zones.get(zone) {
+            Some(existing) => existing.iter().copied().collect(),
+            None => vec![],
+        };
+        cells.extend_from_slice(h3lls);
+        let compacted: BTreeSet<u64> = compact_cells(&cells).into_iter().collect();
+
+        self.remove(zone);
+        for h3ll in &compacted {
+            self.cells.entry(*h3ll).or_insert_with(BTreeSet::new).insert(zone.to_string());
+        }
+        let len = compacted.len();
+        self.zones.insert(zone.to_string(), compacted);
+        len
+    }
+
+    /// remove zone, returns true if it was in the index
+    pub fn remove(&mut self, zone: &str) -> bool {
+        let cells = match self.zones.remove(zone) {
+            Some(cells) => cells,
+            None => return false,
+        };
+        for h3ll in cells {
+            if let Some(zones) = self.cells.get_mut(&h3ll) {
+                zones.remove(zone);
+                if zones.is_empty() {
+                    self.cells.remove(&h3ll);
+                }
+            }
+        }
+        true
+    }
+
+    /// get the ids of the zones containing the cell of h3ll (usually a res 15 index)
+    pub fn query(&self, h3ll: u64) -> BTreeSet<String> {
+        let mut zones: BTreeSet<String> = BTreeSet::new();
+        for cell in once(h3ll).chain(index_ancestors(h3ll)) {
+            if let Some(cell_zones) = self.cells.get(&cell) {
+                zones.extend(cell_zones.iter().cloned());
+            }
+        }
+        zones
+    }
+
+    /// iterate over zone ids with their cells, in zone id order
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &BTreeSet<u64>)> + '_ {
+        let mut zones: Vec<(&String, &BTreeSet<u64>)> = self.zones.iter().collect();
+        zones.sort();
+        zones.into_iter()
+    }
+}
+
+unsafe extern "C" fn rdb_load(rdb: *mut rawmod::RedisModuleIO, encver: c_int) -> *mut c_void {
+    if encver != H3ZONES_ENCODING_VERSION {
+        return null_mut();
+    }
+
+    let mut index = ZoneIndex::new();
+    let len = rawmod::load_unsigned(rdb);
+    for _ in 0..len {
+        let zone = rawmod::load_string(rdb);
+        let cells_len = rawmod::load_unsigned(rdb);
+        let cells: Vec<u64> = (0..cells_len).map(|_| rawmod::load_unsigned(rdb)).collect();
+        index.add(&zone, &cells);
+    }
+    Box::into_raw(Box::new(index)) as *mut c_void
+}
+
+unsafe extern "C" fn rdb_save(rdb: *mut rawmod::RedisModuleIO, value: *mut c_void) {
+    let index = &*(value as *mut ZoneIndex);
+    rawmod::save_unsigned(rdb, index.len() as u64);
+    for (zone, cells) in index.iter() {
+        rawmod::save_string(rdb, zone);
+        rawmod::save_unsigned(rdb, cells.len() as u64);
+        for h3ll in cells {
+            rawmod::save_unsigned(rdb, *h3ll);
+        }
+    }
+}
+
+unsafe extern "C" fn aof_rewrite(aof: *mut rawmod::RedisModuleIO, key: *mut rawmod::RedisModuleString,
+                                 value: *mut c_void) {
+    let index = &*(value as *mut ZoneIndex);
+    // one command per zone, as each H3.ZONEADD compacts all of the zone's cells again
+    for (zone, cells) in index.iter() {
+        let mut args: Vec<String> = Vec::with_capacity(2 + cells.len());
+        args.push(zone.clone());
+        args.push(String::from("CELLS"));
+        args.extend(cells.iter().map(|h3ll| format!("{:x}", h3ll)));
+        emit_aof_vector(aof, "H3.ZONEADD", key, &args);
+    }
+}
+
+unsafe extern "C" fn mem_usage(value: *const c_void) -> usize {
+    let index = &*(value as *const ZoneIndex);
+    // each zone's cells are stored in both maps, along with a copy of the zone id per cell
+    let mut size = size_of::<ZoneIndex>();
+    for (zone, cells) in index.iter() {
+        size += size_of::<String>() + zone.len() + size_of::<BTreeSet<u64>>();
+        size += cells.len() * (2 * size_of::<u64>() + size_of::<String>() + zone.len());
+    }
+    size
+}
+
+unsafe extern "C" fn digest(md: *mut rawmod::RedisModuleDigest, value: *mut c_void) {
+    let index = &*(value as *mut ZoneIndex);
+    for (zone, cells) in index.iter() {
+        rawmod::RedisModule_DigestAddStringBuffer.unwrap()(md, zone.as_ptr() as *mut _, zone.len());
+        for h3ll in cells {
+            rawmod::RedisModule_DigestAddLongLong.unwrap()(md, *h3ll as i64);
+        }
+        rawmod::RedisModule_DigestEndSequence.unwrap()(md);
+    }
+}
+
+unsafe extern "C" fn free(value: *mut c_void) {
+    drop(Box::from_raw(value as *mut ZoneIndex));
+}
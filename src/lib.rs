@@ -1,18 +1,40 @@
 #[macro_use]
 extern crate redis_module;
 
-use std::os::raw::c_int;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::iter::Peekable;
+use std::os::raw::{c_char, c_int};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering as AtomicOrdering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use h3_rs::{GeoCoord, H3Index};
 use redis_module::{NextArg, raw as rawmod};
 use redis_module::{Context, RedisError, RedisResult, RedisValue};
 
-use crate::geoutil::{geohash_get_distance};
-use crate::h3util::{h3ll_to_score, index_max_child, index_min_child,
-                    MAX_RESOLUTION, score_to_h3ll, str_to_h3};
+use crate::geoparse::{distinct_positions, parse_geojson, parse_wkt, valid_position};
+use crate::geoutil::{geohash_get_distance, geohash_get_distance_if_in_rectangle, geojson_feature,
+                     geojson_feature_collection, GEO_LAT_MAX, GEO_LAT_MIN, GEO_LONG_MAX, GEO_LONG_MIN,
+                     json_string, MAX_EARTH_DISTANCE_IN_METERS, point_to_geojson, Polygon,
+                     ring_to_geojson, ring_to_wkt};
+use crate::h3history::{PositionHistory, H3HISTORY_TYPE};
+use crate::h3set::{H3Set, H3SET_TYPE};
+use crate::h3zones::{ZoneIndex, H3ZONES_TYPE};
+use crate::h3util::{cell_boundary, compact_cells, h3_to_h3ll, h3ll_to_score, index_ancestors, index_base_cell,
+                    index_center_child, index_children, index_children_count, index_descendant_range,
+                    index_is_pentagon, index_parent, index_resolution, kring_covered_radius,
+                    MAX_RES_POLYFILL_CELLS, MAX_RESOLUTION, merge_cell_ranges, MIN_RESOLUTION, polyfill,
+                    polyfill_cell_estimate, polyfill_resolution, radius_to_kring, ring_cells, score_to_h3ll, str_to_h3};
 
 mod h3util;
+mod h3set;
+mod h3zones;
+mod h3history;
+mod nativeutil;
 mod geoutil;
+mod geoparse;
 
 ///
 /// H3.STATUS
@@ -24,49 +46,436 @@ fn h3status_command(_ctx: &Context, _args: Vec<String>) -> RedisResult {
     Ok(status.into())
 }
 
+// whether H3.ADD accepts latitudes all the way to the poles, by default it only accepts the
+// latitudes GEOADD does (see H3.CONFIG)
+static FULL_LATITUDE_RANGE: AtomicBool = AtomicBool::new(false);
+// the resolutions at which H3.ADD and H3.ADDBYINDEX emit cell-transition events, one bit per
+// resolution (see H3.CONFIG)
+static WATCH_RESOLUTIONS: AtomicU16 = AtomicU16::new(0);
+// the stream that cell-transition events are added to, if not empty (see H3.CONFIG)
+static TRANSITION_STREAM: Mutex<String> = Mutex::new(String::new());
+// the approximate max length the transition stream is trimmed to, 0 to not trim it (see H3.CONFIG)
+static TRANSITION_STREAM_MAXLEN: AtomicUsize = AtomicUsize::new(10000);
+// whether H3.ADD and H3.ADDBYINDEX record the time each element was last updated (see H3.CONFIG)
+static TRACK_TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+// the number of positions H3.ADD and H3.ADDBYINDEX keep in each element's history, 0 to keep no
+// history (see H3.CONFIG)
+static HISTORY_LENGTH: AtomicUsize = AtomicUsize::new(0);
+
+const UNKNOWN_CONFIG_PARAM_MSG: &str = "Unknown config param (must be latitude-range, watch-resolutions, \
+    transition-stream, transition-stream-maxlen, timestamps or history-length)";
+
+/// get the value of a module config param
+fn get_config(param: &str) -> Result<String, RedisError> {
+    match param {
+        "latitude-range" => {
+            let range = if FULL_LATITUDE_RANGE.load(AtomicOrdering::Relaxed) { "full" } else { "geo" };
+            Ok(String::from(range))
+        },
+        "watch-resolutions" => {
+            let watched = WATCH_RESOLUTIONS.load(AtomicOrdering::Relaxed);
+            let resolutions: Vec<String> = (MIN_RESOLUTION..=MAX_RESOLUTION)
+                .filter(|res| watched & (1 << res) != 0)
+                .map(|res| res.to_string())
+                .collect();
+            Ok(resolutions.join(","))
+        },
+        "transition-stream" => Ok(TRANSITION_STREAM.lock().unwrap().clone()),
+        "transition-stream-maxlen" => Ok(TRANSITION_STREAM_MAXLEN.load(AtomicOrdering::Relaxed).to_string()),
+        "timestamps" => {
+            let timestamps = if TRACK_TIMESTAMPS.load(AtomicOrdering::Relaxed) { "yes" } else { "no" };
+            Ok(String::from(timestamps))
+        },
+        "history-length" => Ok(HISTORY_LENGTH.load(AtomicOrdering::Relaxed).to_string()),
+        _ => Err(RedisError::Str(UNKNOWN_CONFIG_PARAM_MSG))
+    }
+}
+
+/// set the value of a module config param
+fn set_config(param: &str, value: &str) -> Result<(), RedisError> {
+    match param {
+        "latitude-range" => match value.to_lowercase().as_str() {
+            "geo" => FULL_LATITUDE_RANGE.store(false, AtomicOrdering::Relaxed),
+            "full" => FULL_LATITUDE_RANGE.store(true, AtomicOrdering::Relaxed),
+            _ => return Err(RedisError::Str("Invalid latitude-range (must be geo or full)"))
+        },
+        "watch-resolutions" => {
+            let mut watched: u16 = 0;
+            for res in value.split(',').map(|res| res.trim()).filter(|res| !res.is_empty()) {
+                match res.parse::<i32>() {
+                    Ok(res) if res >= MIN_RESOLUTION && res <= MAX_RESOLUTION => watched |= 1 << res,
+                    _ => return Err(RedisError::Str(
+                        "Invalid watch-resolutions (must be a comma separated list of resolutions 0-15)"
+                    ))
+                }
+            }
+            WATCH_RESOLUTIONS.store(watched, AtomicOrdering::Relaxed);
+        },
+        "transition-stream" => *TRANSITION_STREAM.lock().unwrap() = String::from(value),
+        "transition-stream-maxlen" => match value.parse::<usize>() {
+            Ok(maxlen) => TRANSITION_STREAM_MAXLEN.store(maxlen, AtomicOrdering::Relaxed),
+            Err(_err) => return Err(RedisError::Str(
+                "Invalid transition-stream-maxlen (must be a non-negative integer)"
+            ))
+        },
+        "timestamps" => match value.to_lowercase().as_str() {
+            "yes" => TRACK_TIMESTAMPS.store(true, AtomicOrdering::Relaxed),
+            "no" => TRACK_TIMESTAMPS.store(false, AtomicOrdering::Relaxed),
+            _ => return Err(RedisError::Str("Invalid timestamps (must be yes or no)"))
+        },
+        "history-length" => match value.parse::<usize>() {
+            Ok(length) => HISTORY_LENGTH.store(length, AtomicOrdering::Relaxed),
+            Err(_err) => return Err(RedisError::Str("Invalid history-length (must be a non-negative integer)"))
+        },
+        _ => return Err(RedisError::Str(UNKNOWN_CONFIG_PARAM_MSG))
+    }
+    Ok(())
+}
+
+///
+/// H3.CONFIG GET param
+/// H3.CONFIG SET param value
+///
+/// gets or sets the module's config (which, like CONFIG SET, is an admin command and isn't
+/// persisted or replicated):
+///
+/// latitude-range - the range of latitudes H3.ADD accepts: geo (the default) is the range GEOADD
+///     accepts (-85.05112878 to 85.05112878), full is -90 to 90
+/// watch-resolutions - a comma separated list of the resolutions at which H3.ADD and
+///     H3.ADDBYINDEX emit cell-transition events (see emit_transitions), empty by default
+/// transition-stream - the key of a stream that cell-transition events are added to, empty
+///     (the default) to only emit keyspace notifications. In a cluster it has to be in the same
+///     slot as the H3 keys (e.g. with a hash tag)
+/// transition-stream-maxlen - the approximate max length (MAXLEN ~) the transition stream is
+///     trimmed to when events are added, 10000 by default, 0 to not trim it
+/// timestamps - yes to have H3.ADD and H3.ADDBYINDEX record the time each element was last
+///     updated (see H3.UPDATED), no (the default) to not
+/// history-length - the number of (h3idx, ts) positions H3.ADD and H3.ADDBYINDEX keep for each
+///     element (see H3.HISTORY), 0 (the default) to keep no history
+///
+fn h3config_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.CONFIG GET param or H3.CONFIG SET param value";
+    if args.len() < 3 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let param = args[2].to_lowercase();
+    match (args[1].to_uppercase().as_str(), args.len()) {
+        ("GET", 3) => Ok(vec![param.clone(), get_config(&param)?].into()),
+        ("SET", 4) => {
+            set_config(&param, &args[3])?;
+            Ok(RedisValue::SimpleString(String::from("OK")))
+        },
+        _ => Err(RedisError::Str(syntax_err_msg))
+    }
+}
+
+/// check a lng/lat position against the limits GEOADD uses (with the latitude range set by
+/// H3.CONFIG), with normalize a longitude outside of -180..180 is wrapped around into it instead
+fn valid_lng_lat(lng: f64, lat: f64, normalize: bool) -> Option<(f64, f64)> {
+    let (lat_min, lat_max) = if FULL_LATITUDE_RANGE.load(AtomicOrdering::Relaxed) {
+        (-90.0, 90.0)
+    } else {
+        (GEO_LAT_MIN, GEO_LAT_MAX)
+    };
+    if !lng.is_finite() || !lat.is_finite() || lat < lat_min || lat > lat_max {
+        return None;
+    }
+    if lng >= GEO_LONG_MIN && lng <= GEO_LONG_MAX {
+        Some((lng, lat))
+    } else if normalize {
+        Some(((lng - GEO_LONG_MIN).rem_euclid(360.0) + GEO_LONG_MIN, lat))
+    } else {
+        None
+    }
+}
+
+/// publish a keyspace notification (of the generic class) for key
+fn notify_keyspace_event(ctx: &Context, event: &str, key: &str) {
+    let event = CString::new(event).unwrap();
+    unsafe {
+        let keyname = rawmod::RedisModule_CreateString.unwrap()(ctx.ctx, key.as_ptr() as *const c_char, key.len());
+        rawmod::RedisModule_NotifyKeyspaceEvent.unwrap()(
+            ctx.ctx, rawmod::REDISMODULE_NOTIFY_GENERIC as c_int, event.as_ptr(), keyname
+        );
+        rawmod::RedisModule_FreeString.unwrap()(ctx.ctx, keyname);
+    }
+}
+
+/// get the moves of the elements being added, as (name, previous index, new index), given the
+/// elements' indices before the command (None for new elements). With nx or xx (see ZADD) the
+/// elements that won't be added or updated don't move.
+fn element_moves(entries: &[(String, u64)], prev: &[Option<H3Index>], nx: bool,
+                 xx: bool) -> Vec<(String, Option<u64>, u64)> {
+    // an element can be given more than once, each time it moves from where the last one put it
+    let mut current: HashMap<&str, Option<u64>> = HashMap::new();
+    let mut moves: Vec<(String, Option<u64>, u64)> = Vec::with_capacity(entries.len());
+    for ((name, h3ll), prev) in entries.iter().zip(prev) {
+        let from = *current.entry(name.as_str()).or_insert_with(|| prev.as_ref().map(h3_to_h3ll));
+        if (nx && from.is_some()) || (xx && from.is_none()) {
+            continue;
+        }
+        moves.push((name.clone(), from, *h3ll));
+        current.insert(name.as_str(), Some(*h3ll));
+    }
+    moves
+}
+
+///
+/// emit_transitions
+///
+/// Emits the cell-transition events of elements of key moving from their previous indices (None
+/// for new elements) to new ones. For each watch resolution (see H3.CONFIG) at which an
+/// element's cell changes, h3.leave:<old cell> and h3.enter:<new cell> keyspace notifications
+/// are published for key, and if there is a transition stream an entry with the key, member,
+/// resolution, old cell, new cell and timestamp ts (in ms) is added to it (trimming it to about
+/// the transition-stream-maxlen). Elements with indices of a lower resolution than a watch
+/// resolution don't have a cell at it.
+///
+fn emit_transitions(ctx: &Context, key: &str, moves: &[(String, Option<u64>, u64)], ts: u64) -> Result<(), RedisError> {
+    let watched = WATCH_RESOLUTIONS.load(AtomicOrdering::Relaxed);
+    if watched == 0 {
+        return Ok(());
+    }
+    let stream = TRANSITION_STREAM.lock().unwrap().clone();
+    let maxlen = TRANSITION_STREAM_MAXLEN.load(AtomicOrdering::Relaxed);
+
+    for (name, prev, h3ll) in moves {
+        for res in (MIN_RESOLUTION..=MAX_RESOLUTION).filter(|res| watched & (1 << res) != 0) {
+            let res = res as u8;
+            let cell_at = |h3ll: u64| if index_resolution(h3ll) >= res { Some(index_parent(h3ll, res)) } else { None };
+            let (from, to) = (prev.and_then(cell_at), cell_at(*h3ll));
+            if from == to {
+                continue;
+            }
+            if let Some(from) = from {
+                notify_keyspace_event(ctx, &format!("h3.leave:{:x}", from), key);
+            }
+            if let Some(to) = to {
+                notify_keyspace_event(ctx, &format!("h3.enter:{:x}", to), key);
+            }
+            if !stream.is_empty() {
+                let cell_str = |cell: Option<u64>| cell.map(|cell| format!("{:x}", cell)).unwrap_or_default();
+                let mut xaddargs: Vec<String> = vec![stream.clone()];
+                if maxlen > 0 {
+                    xaddargs.extend(vec![String::from("maxlen"), String::from("~"), maxlen.to_string()]);
+                }
+                xaddargs.extend(vec![
+                    String::from("*"),
+                    String::from("key"), String::from(key),
+                    String::from("member"), name.clone(),
+                    String::from("res"), res.to_string(),
+                    String::from("from"), cell_str(from),
+                    String::from("to"), cell_str(to),
+                    String::from("ts"), ts.to_string(),
+                ]);
+                let xaddargs: Vec<&str> = xaddargs.iter().map(|s| s.as_str()).collect();
+                ctx.call("xadd", &xaddargs[..])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the current time in ms since the epoch
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+// the last-updated times and position histories of the elements of key are stored in the
+// h3history key + HISTORY_KEY_SUFFIX (see H3.HISTORY), in a cluster key has to have a hash tag
+// (e.g. {fleet}) for both to be in the same slot
+const HISTORY_KEY_SUFFIX: &str = ":history";
+
+/// check whether H3.ADD and H3.ADDBYINDEX record the times or history of the elements they move
+fn tracking_positions() -> bool {
+    TRACK_TIMESTAMPS.load(AtomicOrdering::Relaxed) || HISTORY_LENGTH.load(AtomicOrdering::Relaxed) > 0
+}
+
+///
+/// record_positions
 ///
-/// H3.ADD key lng lat name [lng2 lat2 name2 ... lngN latN nameN]
+/// Records the moves of elements of key at time ts (in ms) in the history key of key
+/// (key:history): with timestamps (see H3.CONFIG) ts becomes each element's last-updated time
+/// (if it is later), and with a history-length the (h3idx, ts) position is also added to each
+/// element's history, which keeps the history-length latest positions. The histories of elements
+/// that were removed from key without H3.REMBYINDEX (e.g. with ZREM) are pruned once the history
+/// key has more than twice as many elements as key, so that pruning is amortized over the updates.
+///
+fn record_positions(ctx: &Context, key: &str, moves: &[(String, Option<u64>, u64)], ts: u64) -> Result<(), RedisError> {
+    if moves.is_empty() || !tracking_positions() {
+        return Ok(());
+    }
+    let history_length = HISTORY_LENGTH.load(AtomicOrdering::Relaxed);
+
+    let history_key = ctx.open_key_writable(&format!("{}{}", key, HISTORY_KEY_SUFFIX));
+    let record = |history: &mut PositionHistory| {
+        for (name, _prev, h3ll) in moves {
+            history.record(name, *h3ll, ts, history_length);
+        }
+    };
+    match history_key.get_value::<PositionHistory>(&H3HISTORY_TYPE)? {
+        Some(history) => {
+            record(history);
+            prune_histories(ctx, key, history)?;
+        },
+        None => {
+            let mut history = PositionHistory::new();
+            record(&mut history);
+            history_key.set_value(&H3HISTORY_TYPE, history)?;
+        }
+    }
+    Ok(())
+}
+
+/// remove the histories of elements that aren't in key anymore, if history has more than twice
+/// as many elements as key
+fn prune_histories(ctx: &Context, key: &str, history: &mut PositionHistory) -> Result<(), RedisError> {
+    let card = match ctx.call("zcard", &[key])? {
+        RedisValue::Integer(card) => card as usize,
+        _ => 0
+    };
+    if history.len() <= 2 * card {
+        return Ok(());
+    }
+    let names: Vec<String> = history.iter().map(|(name, _member_history)| name.clone()).collect();
+    for (name, opt_idx) in names.iter().zip(get_zscores_as_h3_indices(ctx, &key.to_string(), names.clone())?) {
+        if opt_idx.is_none() {
+            history.remove(name);
+        }
+    }
+    Ok(())
+}
+
+/// remove the histories of elements removed from key (in key:history), a history key holding a
+/// value of another type is just an unrelated key
+fn remove_histories(ctx: &Context, key: &str, names: &[&str]) -> Result<(), RedisError> {
+    let history_key = ctx.open_key_writable(&format!("{}{}", key, HISTORY_KEY_SUFFIX));
+    if let Ok(Some(history)) = history_key.get_value::<PositionHistory>(&H3HISTORY_TYPE) {
+        for name in names {
+            history.remove(name);
+        }
+        if history.len() == 0 {
+            history_key.delete()?;
+        }
+    }
+    Ok(())
+}
+
+/// the options of H3.ADD, which go before its elements
+struct AddOptions {
+    normalize: bool,
+    returnprev: bool,
+    ts: Option<u64>,
+    // NX, XX and CH, which are passed on to ZADD
+    zaddopts: Vec<String>,
+    nx: bool,
+    xx: bool,
+}
+
+impl AddOptions {
+    /// parse the options at the start of args, up to the first arg that isn't one
+    fn parse<I: Iterator<Item = String>>(args: &mut Peekable<I>) -> Result<AddOptions, RedisError> {
+        let mut opts = AddOptions {
+            normalize: false,
+            returnprev: false,
+            ts: None,
+            zaddopts: Vec::new(),
+            nx: false,
+            xx: false,
+        };
+        while let Some(arg) = args.peek() {
+            match arg.to_uppercase().as_str() {
+                "NORMALIZE" => opts.normalize = true,
+                "RETURNPREV" => opts.returnprev = true,
+                "TS" => {
+                    args.next();
+                    match args.peek().map(|value| value.parse::<u64>()) {
+                        Some(Ok(value)) => opts.ts = Some(value),
+                        _ => return Err(RedisError::Str("Invalid TS value (must be ms since the epoch)"))
+                    }
+                },
+                opt @ "NX" | opt @ "XX" | opt @ "CH" => opts.zaddopts.push(opt.to_lowercase()),
+                _ => break
+            }
+            args.next();
+        }
+        opts.nx = opts.zaddopts.iter().any(|opt| opt == "nx");
+        opts.xx = opts.zaddopts.iter().any(|opt| opt == "xx");
+        if opts.nx && opts.xx {
+            return Err(RedisError::Str("XX and NX options at the same time are not compatible"));
+        }
+        Ok(opts)
+    }
+}
+
+///
+/// H3.ADD key [NX|XX] [CH] [RETURNPREV] [NORMALIZE] [TS ts] lng lat name [lng2 lat2 name2 ... lngN latN nameN]
 ///
 /// this is an attempted rust "translation" of geoaddCommand into an
-/// equivalent command for H3
+/// equivalent command for H3. Like GEOADD, nothing is added if any position is invalid (see
+/// valid_lng_lat), with NORMALIZE longitudes outside of -180..180 are wrapped around instead.
+/// NX, XX and CH are the same as for GEOADD. With RETURNPREV an array of each element's H3 index
+/// before the command (nil for new elements) is returned instead of the number of elements.
+/// Elements that move emit cell-transition events (see emit_transitions) and geofence events
+/// (see publish_fence_events), and their positions are recorded at time ts (in ms since the
+/// epoch, by default the current time) if timestamps or history are kept (see record_positions).
 ///
 fn h3add_command(ctx: &Context, args: Vec<String>) -> RedisResult {
-    if args.len() < 5 || (args.len() - 2) % 3 != 0 {
-        return Err(RedisError::Str(
-            "syntax error. Try H3.ADD key [lng1] [lat1] [name1] [lng2] [lat2] [name2] ... "
-        ));
+    let syntax_err_msg = "syntax error. Try H3.ADD key [NX|XX] [CH] [RETURNPREV] [NORMALIZE] [TS ts] \
+        [lng1] [lat1] [name1] [lng2] [lat2] [name2] ... ";
+    if args.len() < 5 {
+        return Err(RedisError::Str(syntax_err_msg));
     }
 
-    let mut args = args.into_iter().skip(1);
+    let mut args = args.into_iter().skip(1).peekable();
     let key = args.next_string()?;
 
+    let AddOptions { normalize, returnprev, ts, mut zaddopts, nx, xx } = AddOptions::parse(&mut args)?;
+    if args.len() == 0 || args.len() % 3 != 0 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
     let elements: usize = args.len() / 3;
-    let argc: usize = 2 + elements * 2; /* ZADD key score elem ... */
+    let argc: usize = 2 + zaddopts.len() + elements * 2; /* ZADD key [options] score elem ... */
 
     let mut newargs: Vec<String> = Vec::with_capacity(argc);
-    newargs.push(key);
+    newargs.push(key.clone());
+    newargs.append(&mut zaddopts);
+    let mut entries: Vec<(String, u64)> = Vec::with_capacity(elements);
 
     /* Create the argument vector to call ZADD in order to add all
      * the score,value pairs to the requested zset, where score is actually
      * an encoded version of lat,long. */
     while args.len() > 0 {
-        match (args.next_f64(), args.next_f64()) {
-            (Ok(lng), Ok(lat)) => {
-                let name = args.next_string()?;
-                // TODO: need to validate lng/lat (should probably happen in GeoCoord::new)
-                let coord: GeoCoord = GeoCoord::new(lat, lng);
-                let h3_from_coord = coord.to_h3(MAX_RESOLUTION).unwrap();
-                let h3ll: u64 = u64::from_str_radix(h3_from_coord.to_string().as_str(), 16).unwrap();
-                let score: f64 = h3ll_to_score(h3ll);
+        let (lng_arg, lat_arg, name) = (args.next_string()?, args.next_string()?, args.next_string()?);
+        let position = match (lng_arg.parse::<f64>(), lat_arg.parse::<f64>()) {
+            (Ok(lng), Ok(lat)) => valid_lng_lat(lng, lat, normalize),
+            _ => None
+        };
+        let h3_from_coord = match position.map(|(lng, lat)| GeoCoord::new(lat, lng).to_h3(MAX_RESOLUTION)) {
+            Some(Ok(h3idx)) => h3idx,
+            _ => return Err(RedisError::String(format!(
+                "invalid longitude,latitude pair {},{} for element {}", lng_arg, lat_arg, name
+            )))
+        };
+        let h3ll = h3_to_h3ll(&h3_from_coord);
+        let score: f64 = h3ll_to_score(h3ll);
 
-                newargs.push(format!("{}", score));
-                newargs.push(name.clone());
-            },
-            _ => return Err(RedisError::Str("Invalid lng or lat value"))
-        }
+        newargs.push(format!("{}", score));
+        newargs.push(name.clone());
+        entries.push((name, h3ll));
     }
 
-    // println!("{:?}", newargs);
+    // the previous indices have to be read before they are overwritten
+    let fenced = has_fences(ctx, &key)?;
+    let watching = WATCH_RESOLUTIONS.load(AtomicOrdering::Relaxed) != 0 || tracking_positions() || fenced;
+    let prev = if returnprev || watching {
+        let names: Vec<String> = entries.iter().map(|(name, _h3ll)| name.clone()).collect();
+        Some(get_zscores_as_h3_indices(ctx, &key, names)?)
+    } else {
+        None
+    };
 
     let newvec: Vec<&str> = newargs.iter().map(|s| {
         s.as_str()
@@ -74,7 +483,30 @@ fn h3add_command(ctx: &Context, args: Vec<String>) -> RedisResult {
     let newvec = &newvec[..];
 
     // call zadd with newargs
-    ctx.call("zadd", newvec)
+    let added = ctx.call("zadd", newvec)?;
+
+    if let (true, Some(prev)) = (watching, &prev) {
+        let moves = element_moves(&entries, prev, nx, xx);
+        let ts = ts.unwrap_or_else(now_ms);
+        emit_transitions(ctx, &key, &moves, ts)?;
+        if fenced {
+            publish_fence_events(ctx, &key, &moves)?;
+        }
+        record_positions(ctx, &key, &moves, ts)?;
+    }
+
+    match prev {
+        Some(prev) if returnprev => {
+            let prev: Vec<RedisValue> = prev.iter().map(|opt_idx| {
+                match opt_idx {
+                    Some(h3idx) => h3idx.to_string().into(),
+                    None => RedisValue::Null
+                }
+            }).collect();
+            Ok(prev.into())
+        },
+        _ => Ok(added)
+    }
 }
 
 ///
@@ -82,7 +514,12 @@ fn h3add_command(ctx: &Context, args: Vec<String>) -> RedisResult {
 ///
 /// this is an alternate to H3.ADD that takes an H3Index instead of lng/lat
 ///
-/// NOTE: h3idx must have resolution 15 to be considered valid, otherwise an error is raised
+/// NOTE: h3idx can have any resolution, an element added with a lower resolution index
+/// represents its whole cell (see H3.CELL and H3.COUNT)
+///
+/// like H3.ADD, cell-transition events are emitted at the watch resolutions (see H3.CONFIG),
+/// geofence events are published (see H3.FENCEADD) and positions are recorded at the current
+/// time if timestamps or history are kept
 ///
 fn h3addbyindex_command(ctx: &Context, args: Vec<String>) -> RedisResult {
     if args.len() < 4 || args.len() % 2 != 0 {
@@ -98,7 +535,8 @@ fn h3addbyindex_command(ctx: &Context, args: Vec<String>) -> RedisResult {
     let argc: usize = 2+elements*2; /* ZADD key score elem ... */
 
     let mut newargs: Vec<String> = Vec::with_capacity(argc);
-    newargs.push(key);
+    newargs.push(key.clone());
+    let mut entries: Vec<(String, u64)> = Vec::with_capacity(elements);
 
     while args.len() > 0 {
         let h3key = args.next_string()?;
@@ -106,10 +544,6 @@ fn h3addbyindex_command(ctx: &Context, args: Vec<String>) -> RedisResult {
 
         match str_to_h3(&h3key) {
             Ok(h3idx) => {
-                // verify resolution 15
-                if h3idx.resolution() != MAX_RESOLUTION {
-                    return Err(RedisError::Str("Invalid h3idx resolution (must be 15)"))
-                }
                 // this line is not optimal, the line after would be put member is not pub
                 let h3ll = u64::from_str_radix(h3idx.to_string().as_str(), 16).unwrap();
                 // let H3Index(h3ll) = h3idx;
@@ -117,12 +551,20 @@ fn h3addbyindex_command(ctx: &Context, args: Vec<String>) -> RedisResult {
 
                 newargs.push(format!("{}", score));
                 newargs.push(name.clone());
+                entries.push((name, h3ll));
             },
             Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
         }
     }
 
-    // println!("{:?}", newargs);
+    // the previous indices have to be read before they are overwritten
+    let fenced = has_fences(ctx, &key)?;
+    let prev = if WATCH_RESOLUTIONS.load(AtomicOrdering::Relaxed) != 0 || tracking_positions() || fenced {
+        let names: Vec<String> = entries.iter().map(|(name, _h3ll)| name.clone()).collect();
+        Some(get_zscores_as_h3_indices(ctx, &key, names)?)
+    } else {
+        None
+    };
 
     let newvec: Vec<&str> = newargs.iter().map(|s| {
         s.as_str()
@@ -130,7 +572,18 @@ fn h3addbyindex_command(ctx: &Context, args: Vec<String>) -> RedisResult {
     let newvec = &newvec[..];
 
     // call zadd with newargs
-    ctx.call("zadd", newvec)
+    let added = ctx.call("zadd", newvec)?;
+
+    if let Some(prev) = prev {
+        let moves = element_moves(&entries, &prev, false, false);
+        let ts = now_ms();
+        emit_transitions(ctx, &key, &moves, ts)?;
+        if fenced {
+            publish_fence_events(ctx, &key, &moves)?;
+        }
+        record_positions(ctx, &key, &moves, ts)?;
+    }
+    Ok(added)
 }
 
 ///
@@ -157,7 +610,6 @@ fn get_zscores(ctx: &Context, key: &String, elems: Vec<String>) -> RedisResult {
                     // this means an entry wasn't found for the elem, ignoring for now
                     RedisValue::Null => scores.push(RedisValue::Null),
                     _ => {
-                        println!("v: {:?}", v);
                         return Err(RedisError::Str("Unexpected type (SimpleString or Null)"))
                     }
                 }
@@ -192,8 +644,7 @@ fn get_zscores_as_h3_indices(ctx: &Context, key: &String, elems: Vec<String>) ->
                     }
                 }).collect()
         },
-        Ok(v) => {
-            println!("v: {:?}", v);
+        Ok(_) => {
             return Err(RedisError::Str("Unexpected type (not Array)"));
         },
         Err(err) => return Err(err)
@@ -206,15 +657,47 @@ fn get_zscores_as_h3_indices(ctx: &Context, key: &String, elems: Vec<String>) ->
 
 ///
 /// H3.INDEX key elem1 elem2 ... elemN
+/// H3.INDEX key [WITHBOUNDARY [BOUNDARYRES res]] MEMBERS elem1 elem2 ... elemN
 ///
 /// Returns an array with H3Index representations of the positions of
-/// the specified elements ("translation" of geohashCommand)
+/// the specified elements ("translation" of geohashCommand). With WITHBOUNDARY an array of the
+/// H3 index and the boundary vertices of its cell (or of the cell at BOUNDARYRES containing it)
+/// is returned for each element instead. The options go before the elements, which then follow
+/// MEMBERS; without a MEMBERS followed by elements all args are elements, e.g. H3.INDEX key
+/// withboundary returns the index of "withboundary" (see split_member_options).
 ///
 fn h3index_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.INDEX key elem1 ... elemN or \
+        H3.INDEX key [WITHBOUNDARY [BOUNDARYRES res]] MEMBERS elem1 ... elemN";
     let mut args = args.into_iter().skip(1);
     let key = args.next_string()?;
 
-    let args: Vec<String> = args.collect();
+    let (options, args) = split_member_options(args.collect(), &["WITHBOUNDARY", "BOUNDARYRES"]);
+    let mut withboundary = false;
+    let mut boundaryres: Option<u8> = None;
+    let mut options = options.into_iter();
+    while let Ok(arg) = options.next_string() {
+        match arg.to_uppercase().as_str() {
+            "WITHBOUNDARY" => withboundary = true,
+            "BOUNDARYRES" => boundaryres = Some(next_res_arg(&mut options)? as u8),
+            _ => return Err(RedisError::Str(syntax_err_msg))
+        }
+    }
+    let boundary = boundary_option(withboundary, boundaryres)?;
+
+    if let Some(res) = boundary {
+        let mut reply: Vec<RedisValue> = Vec::with_capacity(args.len());
+        for opt_idx in get_zscores_as_h3_indices(&ctx, &key, args)? {
+            match opt_idx {
+                Some(h3idx) => {
+                    let boundary = member_boundary(&h3idx, res)?;
+                    reply.push(vec![h3idx.to_string().into(), boundary_reply(&boundary)].into());
+                },
+                None => reply.push(RedisValue::Null)
+            }
+        }
+        return Ok(reply.into());
+    }
 
     match get_zscores_as_h3_indices(&ctx, &key, args) {
         Ok(vec_opt_h3indices) => {
@@ -230,17 +713,186 @@ fn h3index_command(ctx: &Context, args: Vec<String>) -> RedisResult {
     }
 }
 
+/// the format of the replies of the commands with a FORMAT option
+#[derive(PartialEq)]
+enum ReplyFormat {
+    Array,
+    GeoJson,
+}
+
+/// parse the value of a FORMAT option
+fn next_format_arg<I: Iterator<Item = String>>(args: &mut I) -> Result<ReplyFormat, RedisError> {
+    match args.next_string()?.to_uppercase().as_str() {
+        "GEOJSON" => Ok(ReplyFormat::GeoJson),
+        _ => Err(RedisError::Str("Invalid FORMAT (must be GEOJSON)"))
+    }
+}
+
+/// combine the WITHBOUNDARY and BOUNDARYRES options into the resolution of the cell boundaries
+/// to return, if any (res 15 by default)
+fn boundary_option(withboundary: bool, boundaryres: Option<u8>) -> Result<Option<u8>, RedisError> {
+    match (withboundary, boundaryres) {
+        (true, res) => Ok(Some(res.unwrap_or(MAX_RESOLUTION as u8))),
+        (false, Some(_)) => Err(RedisError::Str("BOUNDARYRES requires WITHBOUNDARY")),
+        (false, None) => Ok(None)
+    }
+}
+
+/// get the boundary of the cell at resolution res that contains the cell of h3idx (the cells of
+/// lower resolution indices are used as they are)
+fn member_boundary(h3idx: &H3Index, res: u8) -> Result<Vec<(f64, f64)>, RedisError> {
+    let h3ll = h3_to_h3ll(h3idx);
+    if index_resolution(h3ll) <= res {
+        return Ok(cell_boundary(h3idx));
+    }
+    match H3Index::new(index_parent(h3ll, res)) {
+        Ok(cell) => Ok(cell_boundary(&cell)),
+        Err(_err) => Err(RedisError::Str("Invalid h3idx value"))
+    }
+}
+
+/// convert boundary vertices to an array of lng/lat arrays
+fn boundary_reply(boundary: &[(f64, f64)]) -> RedisValue {
+    let vertices: Vec<RedisValue> = boundary.iter().map(|(lng, lat)| {
+        vec![lng.to_string(), lat.to_string()].into()
+    }).collect();
+    vertices.into()
+}
+
+/// format a member as a GeoJSON Feature: a Point at the centroid of its H3 index or, with a
+/// boundary resolution, the Polygon of its cell (see member_boundary), with the H3 index and
+/// distance (if any) as properties
+fn member_to_geojson(name: &str, h3idx: &H3Index, dist: Option<f64>,
+                     boundary: Option<u8>) -> Result<String, RedisError> {
+    let geometry = match boundary {
+        Some(res) => ring_to_geojson(&member_boundary(h3idx, res)?),
+        None => {
+            let coord: GeoCoord = h3idx.to_geo();
+            point_to_geojson(coord.lon, coord.lat)
+        }
+    };
+    let mut properties: Vec<(&str, String)> = vec![
+        ("member", json_string(name)),
+        ("h3idx", json_string(&h3idx.to_string())),
+    ];
+    if let Some(dist) = dist {
+        properties.push(("distance", format!("{:.4}", dist)));
+    }
+    Ok(geojson_feature(&geometry, &properties))
+}
+
+/// the members replied by a query command, as an array of names (each followed by its H3 index
+/// with WITHINDICES) or, with FORMAT GEOJSON, as a FeatureCollection (see member_to_geojson)
+struct MembersReply {
+    format: ReplyFormat,
+    withindices: bool,
+    boundary: Option<u8>,
+    values: Vec<RedisValue>,
+    features: Vec<String>,
+}
+
+impl MembersReply {
+    fn new(format: ReplyFormat, withindices: bool, boundary: Option<u8>) -> Result<MembersReply, RedisError> {
+        if boundary.is_some() && format != ReplyFormat::GeoJson {
+            return Err(RedisError::Str("WITHBOUNDARY requires FORMAT GEOJSON"));
+        }
+        Ok(MembersReply { format, withindices, boundary, values: Vec::new(), features: Vec::new() })
+    }
+
+    fn push(&mut self, name: String, h3ll: u64) -> Result<(), RedisError> {
+        let h3idx = match H3Index::new(h3ll) {
+            Ok(h3idx) => h3idx,
+            Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+        };
+        match self.format {
+            ReplyFormat::GeoJson => self.features.push(member_to_geojson(&name, &h3idx, None, self.boundary)?),
+            ReplyFormat::Array => {
+                self.values.push(name.into());
+                if self.withindices {
+                    self.values.push(h3idx.to_string().into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn into_reply(self) -> RedisValue {
+        match self.format {
+            ReplyFormat::GeoJson => geojson_feature_collection(&self.features).into(),
+            ReplyFormat::Array => self.values.into()
+        }
+    }
+}
+
+/// split the args after the key of a command with options that can be given before its elements:
+/// if the first arg is MEMBERS or one of the options and a MEMBERS arg is followed by elements,
+/// the options go up to that MEMBERS arg and the elements after it, otherwise all of the args
+/// are elements (so elements named like an option still work without MEMBERS). Elements named
+/// like an option (or MEMBERS) can always be given after MEMBERS.
+fn split_member_options(args: Vec<String>, options: &[&str]) -> (Vec<String>, Vec<String>) {
+    let is_option = |arg: &String| {
+        let arg = arg.to_uppercase();
+        arg == "MEMBERS" || options.contains(&arg.as_str())
+    };
+    if !args.first().map_or(false, is_option) {
+        return (vec![], args);
+    }
+    match args.iter().position(|arg| arg.to_uppercase() == "MEMBERS") {
+        Some(pos) if pos + 1 < args.len() => {
+            let mut options = args;
+            let elements = options.split_off(pos + 1);
+            options.pop();
+            (options, elements)
+        },
+        _ => (vec![], args)
+    }
+}
+
 ///
 /// H3.POS key elem1 elem2 ... elemN
+/// H3.POS key [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]] MEMBERS elem1 elem2 ... elemN
 ///
 /// Returns an array with lng/lat arrays of the centroids of H3 indices
-/// for the specified elements ("translation" of geoposCommand)
+/// for the specified elements ("translation" of geoposCommand). With FORMAT GEOJSON a
+/// FeatureCollection of the elements that exist is returned instead (see member_to_geojson).
+/// The options go before the elements, which then follow MEMBERS; without a MEMBERS followed by
+/// elements all args are elements, e.g. H3.POS key format returns the position of "format" (see
+/// split_member_options).
 ///
 fn h3pos_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.POS key elem1 ... elemN or \
+        H3.POS key [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]] MEMBERS elem1 ... elemN";
+
     let mut args = args.into_iter().skip(1);
     let key = args.next_string()?;
 
-    let args: Vec<String> = args.collect();
+    let (options, args) = split_member_options(args.collect(), &["FORMAT", "WITHBOUNDARY", "BOUNDARYRES"]);
+    let mut format = ReplyFormat::Array;
+    let mut withboundary = false;
+    let mut boundaryres: Option<u8> = None;
+    let mut options = options.into_iter();
+    while let Ok(arg) = options.next_string() {
+        match arg.to_uppercase().as_str() {
+            "FORMAT" => format = next_format_arg(&mut options)?,
+            "WITHBOUNDARY" => withboundary = true,
+            "BOUNDARYRES" => boundaryres = Some(next_res_arg(&mut options)? as u8),
+            _ => return Err(RedisError::Str(syntax_err_msg))
+        }
+    }
+    let boundary = boundary_option(withboundary, boundaryres)?;
+    if boundary.is_some() && format != ReplyFormat::GeoJson {
+        return Err(RedisError::Str("WITHBOUNDARY requires FORMAT GEOJSON"));
+    }
+
+    if format == ReplyFormat::GeoJson {
+        let mut features: Vec<String> = Vec::new();
+        for (name, opt_idx) in args.iter().zip(get_zscores_as_h3_indices(&ctx, &key, args.clone())?) {
+            if let Some(h3idx) = opt_idx {
+                features.push(member_to_geojson(name, &h3idx, None, boundary)?);
+            }
+        }
+        return Ok(geojson_feature_collection(&features).into());
+    }
 
     match get_zscores_as_h3_indices(&ctx, &key, args) {
         Ok(vec_opt_h3indices) => {
@@ -263,7 +915,7 @@ fn h3pos_command(ctx: &Context, args: Vec<String>) -> RedisResult {
 /// get_cell_members
 ///
 /// Takes an H3 key (cell or index as string) and optional limit values and returns
-/// all elems whose indices are children of the given H3 key
+/// all elems whose indices are the given H3 key or its descendants (at any resolution)
 ///
 fn get_cell_members(ctx: &Context, key: &String, h3idx: &H3Index, withindices: bool, limit: bool,
                     offset: i64, count: i64) -> RedisResult {
@@ -277,13 +929,10 @@ fn get_cell_members(ctx: &Context, key: &String, h3idx: &H3Index, withindices: b
     let h3ll = u64::from_str_radix(h3idx.to_string().as_str(), 16).unwrap();
     // let H3Index(h3ll) = h3idx;
 
-    let min_child = index_min_child(h3ll);
-    let min_score = h3ll_to_score(min_child);
-    let min_score = format!("{}", min_score);
-
-    let max_child = index_max_child(h3ll);
-    let max_score = h3ll_to_score(max_child);
-    let max_score = format!("{}", max_score);
+    // a cell's score comes right after the scores of all of its descendants
+    let (min_key, max_key) = index_descendant_range(h3ll);
+    let min_score = format!("{}", h3ll_to_score(min_key));
+    let max_score = format!("{}", h3ll_to_score(max_key));
 
     let mut newargs: Vec<String> = vec![key.clone(), min_score, max_score];
     if withindices {
@@ -310,7 +959,6 @@ fn get_cell_members(ctx: &Context, key: &String, h3idx: &H3Index, withindices: b
                         let elem: &String = match &elems[i] {
                             RedisValue::SimpleString(s) => s,
                             _ => {
-                                println!("v: {:?}", &v);
                                 return Err(RedisError::Str("Unexpected type (not SimpleString)"))
                             }
                         };
@@ -335,7 +983,6 @@ fn get_cell_members(ctx: &Context, key: &String, h3idx: &H3Index, withindices: b
                 // this means an entry wasn't found for the elem, ignoring for now
                 RedisValue::Null => Ok(RedisValue::Null),
                 _ => {
-                    println!("v: {:?}", v);
                     return Err(RedisError::Str("Unexpected type (not Array or Null)"))
                 }
             }
@@ -344,14 +991,29 @@ fn get_cell_members(ctx: &Context, key: &String, h3idx: &H3Index, withindices: b
     }
 }
 
+/// get (elem, H3Index as u64) pairs for all elems whose indices are ancestors of the given
+/// H3Index as u64 (i.e. elems added at lower resolutions whose cells contain it)
+fn get_ancestor_members_with_indices(ctx: &Context, key: &String, h3ll: u64) -> Result<Vec<(String, u64)>, RedisError> {
+    let mut members: Vec<(String, u64)> = Vec::new();
+    for ancestor in index_ancestors(h3ll) {
+        members.append(&mut get_range_members_with_indices(ctx, key, ancestor, ancestor)?);
+    }
+    Ok(members)
+}
+
 ///
-/// H3.CELL key h3idx [WITHINDICES] [LIMIT offset count]
+/// H3.CELL key h3idx [WITHINDICES] [WITHBOUNDARY [BOUNDARYRES res]] [LIMIT offset count]
+///     [INTERSECTS] [FORMAT GEOJSON]
 ///
 /// Returns an array of the elements in the zset that are contained within the H3 cell
-/// for the given index
+/// for the given index, with INTERSECTS elements whose (lower resolution) cells contain the
+/// H3 cell are returned too. WITHBOUNDARY adds the boundary vertices of each element's cell
+/// (or of the cell at BOUNDARYRES containing it). With FORMAT GEOJSON a FeatureCollection is
+/// returned instead.
 ///
 fn h3cell_command(ctx: &Context, args: Vec<String>) -> RedisResult {
-    let syntax_err_msg = "syntax error. Try H3.CELL key h3idx [WITHINDICES] [LIMIT offset count]";
+    let syntax_err_msg = "syntax error. Try H3.CELL key h3idx [WITHINDICES] [WITHBOUNDARY [BOUNDARYRES res]] \
+        [LIMIT offset count] [INTERSECTS] [FORMAT GEOJSON]";
     if args.len() < 3 {
         return Err(RedisError::Str(syntax_err_msg));
     }
@@ -360,6 +1022,10 @@ fn h3cell_command(ctx: &Context, args: Vec<String>) -> RedisResult {
     let key = args.next_string()?;
     let h3key = args.next_string()?;
     let mut withindices = false;
+    let mut intersects = false;
+    let mut format = ReplyFormat::Array;
+    let mut withboundary = false;
+    let mut boundaryres: Option<u8> = None;
     let mut limit = false;
     let mut offset = 0;
     let mut count = 0;
@@ -374,6 +1040,18 @@ fn h3cell_command(ctx: &Context, args: Vec<String>) -> RedisResult {
             "WITHINDICES" => {
                 withindices = true;
             }
+            "INTERSECTS" => {
+                intersects = true;
+            }
+            "FORMAT" => {
+                format = next_format_arg(&mut args)?;
+            }
+            "WITHBOUNDARY" => {
+                withboundary = true;
+            }
+            "BOUNDARYRES" => {
+                boundaryres = Some(next_res_arg(&mut args)? as u8);
+            }
             "LIMIT" => {
                 limit = true;
                 if args.len() < 2 {
@@ -388,23 +1066,73 @@ fn h3cell_command(ctx: &Context, args: Vec<String>) -> RedisResult {
         }
     }
 
-    get_cell_members(ctx, &key, &h3idx, withindices, limit, offset, count)
+    let boundary = boundary_option(withboundary, boundaryres)?;
+    if !intersects && format == ReplyFormat::Array && boundary.is_none() {
+        return get_cell_members(ctx, &key, &h3idx, withindices, limit, offset, count);
+    }
+
+    // elements of ancestor cells have higher scores than the cell's elements, so they go last
+    let h3ll = h3_to_h3ll(&h3idx);
+    let mut members = get_cell_members_with_indices(ctx, &key, h3ll)?;
+    if intersects {
+        members.append(&mut get_ancestor_members_with_indices(ctx, &key, h3ll)?);
+    }
+
+    let mut members: Vec<(String, u64)> = members.into_iter().skip(offset.max(0) as usize).collect();
+    // like ZRANGEBYSCORE, a negative count means all elements from offset
+    if limit && count >= 0 {
+        members.truncate(count as usize);
+    }
+
+    if format == ReplyFormat::GeoJson {
+        let mut features: Vec<String> = Vec::with_capacity(members.len());
+        for (name, h3ll) in members {
+            match H3Index::new(h3ll) {
+                Ok(h3idx) => features.push(member_to_geojson(&name, &h3idx, None, boundary)?),
+                Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+            }
+        }
+        return Ok(geojson_feature_collection(&features).into());
+    }
+
+    let mut reply: Vec<RedisValue> = Vec::with_capacity(members.len());
+    for (name, h3ll) in members {
+        reply.push(name.into());
+        let h3idx = match H3Index::new(h3ll) {
+            Ok(h3idx) => h3idx,
+            Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+        };
+        if withindices {
+            reply.push(h3idx.to_string().into());
+        }
+        if let Some(res) = boundary {
+            reply.push(boundary_reply(&member_boundary(&h3idx, res)?));
+        }
+    }
+    Ok(reply.into())
 }
 
 ///
-/// H3.COUNT key h3idx
+/// H3.COUNT key h3idx [INTERSECTS]
 ///
 /// this is a translation of the ZCOUNT command that takes an H3Index and returns the number of
-/// elements contained within the H3 cell for the given index
+/// elements contained within the H3 cell for the given index, with INTERSECTS elements whose
+/// (lower resolution) cells contain the H3 cell are counted too
 ///
 fn h3count_command(ctx: &Context, args: Vec<String>) -> RedisResult {
-    if args.len() != 3 {
-        return Err(RedisError::Str("syntax error. Try H3.COUNT key h3idx"));
+    let syntax_err_msg = "syntax error. Try H3.COUNT key h3idx [INTERSECTS]";
+    if args.len() != 3 && args.len() != 4 {
+        return Err(RedisError::Str(syntax_err_msg));
     }
 
     let mut args = args.into_iter().skip(1);
     let key = args.next_string()?;
     let h3key = args.next_string()?;
+    let intersects = match args.next_string() {
+        Ok(arg) if arg.to_uppercase() == "INTERSECTS" => true,
+        Ok(_) => return Err(RedisError::Str(syntax_err_msg)),
+        Err(_) => false
+    };
 
     let h3idx = match str_to_h3(&h3key) {
         Ok(h3idx) => h3idx,
@@ -416,55 +1144,264 @@ fn h3count_command(ctx: &Context, args: Vec<String>) -> RedisResult {
     let h3ll = u64::from_str_radix(h3idx.to_string().as_str(), 16).unwrap();
     // let H3Index(h3ll) = h3idx;
 
-    let min_child = index_min_child(h3ll);
-    let min_score = h3ll_to_score(min_child);
-    let min_score = format!("{}", min_score);
-
-    let max_child = index_max_child(h3ll);
-    let max_score = h3ll_to_score(max_child);
-    let max_score = format!("{}", max_score);
+    // a cell's score comes right after the scores of all of its descendants
+    let (min_key, max_key) = index_descendant_range(h3ll);
+    let min_score = format!("{}", h3ll_to_score(min_key));
+    let max_score = format!("{}", h3ll_to_score(max_key));
 
     let newargs: Vec<&str> = vec![&key, &min_score, &max_score];
     let newargs = &newargs[..];
 
-    ctx.call("zcount", newargs)
+    if !intersects {
+        return ctx.call("zcount", newargs);
+    }
+
+    let mut total: i64 = match ctx.call("zcount", newargs)? {
+        RedisValue::Integer(n) => n,
+        _ => {
+            return Err(RedisError::Str("Unexpected type (not Integer)"))
+        }
+    };
+    total += get_ancestor_members_with_indices(ctx, &key, h3ll)?.len() as i64;
+    Ok(total.into())
+}
+
+/// parse a list of h3idx args that is followed by optional args, returns the indices as u64
+/// along with the first arg that isn't an h3idx (if any)
+fn next_h3_indices<I: Iterator<Item = String>>(args: &mut I) -> Result<(Vec<u64>, Option<String>), RedisError> {
+    let mut h3lls: Vec<u64> = Vec::new();
+    while let Ok(arg) = args.next_string() {
+        match str_to_h3(&arg) {
+            Ok(h3idx) => h3lls.push(h3_to_h3ll(&h3idx)),
+            Err(_err) => {
+                if h3lls.is_empty() {
+                    return Err(RedisError::Str("Invalid h3idx value"));
+                }
+                return Ok((h3lls, Some(arg)));
+            }
+        }
+    }
+    Ok((h3lls, None))
 }
 
 ///
-/// H3.SCAN key cursor [MATCH pattern] [COUNT count]
+/// H3.CELLS key h3idx1 ... h3idxN [WITHINDICES] [LIMIT offset count]
+///     [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]
 ///
-/// this is a translation of the ZSCAN command, but instead of returning elements with scores,
-/// it returns elements with H3 indices
+/// Like H3.CELL for a list of H3 indices of any resolutions, the cells are merged into the
+/// minimal set of index ranges so that each element is returned only once
 ///
-fn h3scan_command(ctx: &Context, args: Vec<String>) -> RedisResult {
-    let syntax_err_msg = "syntax error. Try H3.SCAN key cursor [MATCH pattern] [COUNT count]";
+fn h3cells_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.CELLS key h3idx1 ... h3idxN [WITHINDICES] [LIMIT offset count] \
+        [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]";
     if args.len() < 3 {
         return Err(RedisError::Str(syntax_err_msg));
     }
 
     let mut args = args.into_iter().skip(1);
     let key = args.next_string()?;
-    let cursor = match args.next_i64() {
-        Ok(c) => c,
-        Err(_err) => return Err(RedisError::Str("invalid cursor"))
-    };
-    let mut match_pattern: Option<String> = None;
-    let mut count: Option<i64> = None;
+    let (h3lls, mut next_arg) = next_h3_indices(&mut args)?;
+    let mut withindices = false;
+    let mut format = ReplyFormat::Array;
+    let mut withboundary = false;
+    let mut boundaryres: Option<u8> = None;
+    let mut offset: usize = 0;
+    let mut count: Option<usize> = None;
 
-    while let Ok(arg) = args.next_string() {
+    while let Some(arg) = next_arg {
         match arg.to_uppercase().as_str() {
-            "MATCH" => match_pattern = Some(args.next_string()?),
-            "COUNT" => count = Some(args.next_i64()?),
+            "WITHINDICES" => {
+                withindices = true;
+            }
+            "FORMAT" => {
+                format = next_format_arg(&mut args)?;
+            }
+            "WITHBOUNDARY" => {
+                withboundary = true;
+            }
+            "BOUNDARYRES" => {
+                boundaryres = Some(next_res_arg(&mut args)? as u8);
+            }
+            "LIMIT" => {
+                let (o, c) = (args.next_i64()?, args.next_i64()?);
+                if o < 0 {
+                    return Err(RedisError::Str(syntax_err_msg));
+                }
+                offset = o as usize;
+                // like ZRANGEBYSCORE, a negative count means all elements from offset
+                count = if c < 0 { None } else { Some(c as usize) };
+            }
             _ => {
                 return Err(RedisError::Str(syntax_err_msg));
             }
         }
+        next_arg = args.next_string().ok();
     }
 
-    let mut newargs: Vec<String> = vec![key, cursor.to_string()];
-    if match_pattern.is_some() {
-        newargs.push(String::from("match"));
-        newargs.push(match_pattern.unwrap());
+    let boundary = boundary_option(withboundary, boundaryres)?;
+    let mut reply = MembersReply::new(format, withindices, boundary)?;
+    let mut skipped: usize = 0;
+    let mut found: usize = 0;
+    'ranges: for (min_h3ll, max_h3ll) in merge_cell_ranges(&h3lls) {
+        for (name, h3ll) in get_range_members_with_indices(ctx, &key, min_h3ll, max_h3ll)? {
+            if count == Some(found) {
+                break 'ranges;
+            }
+            if skipped < offset {
+                skipped += 1;
+                continue;
+            }
+            reply.push(name, h3ll)?;
+            found += 1;
+        }
+    }
+
+    Ok(reply.into_reply())
+}
+
+///
+/// H3.COUNTCELLS key h3idx1 ... h3idxN
+///
+/// Like H3.COUNT for a list of H3 indices of any resolutions, returns the total number of
+/// elements contained in the cells (elements in overlapping cells are only counted once)
+///
+fn h3countcells_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::Str("syntax error. Try H3.COUNTCELLS key h3idx1 ... h3idxN"));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let (h3lls, next_arg) = next_h3_indices(&mut args)?;
+    if next_arg.is_some() {
+        return Err(RedisError::Str("Invalid h3idx value"));
+    }
+
+    let mut total: i64 = 0;
+    for (min_h3ll, max_h3ll) in merge_cell_ranges(&h3lls) {
+        let min_score = format!("{}", h3ll_to_score(min_h3ll));
+        let max_score = format!("{}", h3ll_to_score(max_h3ll));
+        match ctx.call("zcount", &[&key, &min_score, &max_score])? {
+            RedisValue::Integer(n) => total += n,
+            _ => {
+                return Err(RedisError::Str("Unexpected type (not Integer)"))
+            }
+        }
+    }
+
+    Ok(total.into())
+}
+
+///
+/// H3.HISTOGRAM key res [WITHIN h3idx]
+///
+/// Returns an array of [h3idx, count] pairs for every cell at resolution res that contains
+/// elements (optionally only the cells within the given H3 index's cell). Since elements are
+/// ordered by their H3 indices, the elements of each cell are next to each other, so this is
+/// done in one pass. Elements with indices of a lower resolution than res aren't in any cell at
+/// res, so they aren't counted.
+///
+fn h3histogram_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.HISTOGRAM key res [WITHIN h3idx]";
+    if args.len() != 3 && args.len() != 5 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let res = args.next_i64()?;
+    if res < MIN_RESOLUTION as i64 || res > MAX_RESOLUTION as i64 {
+        return Err(RedisError::Str("Invalid resolution (must be 0-15)"));
+    }
+
+    let members = match args.next_string() {
+        Ok(arg) if arg.to_uppercase() == "WITHIN" => {
+            let h3idx = match str_to_h3(&args.next_string()?) {
+                Ok(h3idx) => h3idx,
+                Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+            };
+            if (h3idx.resolution() as i64) > res {
+                return Err(RedisError::Str("res must not be lower than the WITHIN h3idx resolution"));
+            }
+            get_cell_members_with_indices(ctx, &key, h3_to_h3ll(&h3idx))?
+        },
+        Ok(_) => return Err(RedisError::Str(syntax_err_msg)),
+        Err(_) => get_score_range_members_with_indices(ctx, &key, "-inf", "+inf")?
+    };
+
+    let mut histogram: Vec<RedisValue> = Vec::new();
+    for (cell, count) in histogram_counts(&members, res as u8) {
+        histogram.push(histogram_entry(cell, count)?);
+    }
+    Ok(histogram.into())
+}
+
+/// count the (name, H3Index as u64) members, in index order, per cell at resolution res,
+/// skipping members with indices of a lower resolution
+fn histogram_counts(members: &[(String, u64)], res: u8) -> Vec<(u64, i64)> {
+    let mut counts: Vec<(u64, i64)> = Vec::new();
+    for (_name, h3ll) in members {
+        if index_resolution(*h3ll) < res {
+            continue;
+        }
+        let cell = index_parent(*h3ll, res);
+        match counts.last_mut() {
+            Some((c, n)) if *c == cell => *n += 1,
+            _ => counts.push((cell, 1))
+        }
+    }
+    counts
+}
+
+fn histogram_entry(h3ll: u64, count: i64) -> RedisResult {
+    match H3Index::new(h3ll) {
+        Ok(h3idx) => Ok(vec![h3idx.to_string().into(), RedisValue::Integer(count)].into()),
+        Err(_err) => Err(RedisError::Str("Invalid h3idx value"))
+    }
+}
+
+///
+/// H3.SCAN key cursor [MATCH pattern] [COUNT count] [WITHBOUNDARY [BOUNDARYRES res]]
+///
+/// this is a translation of the ZSCAN command, but instead of returning elements with scores,
+/// it returns elements with H3 indices, and with WITHBOUNDARY the boundary vertices of each
+/// element's cell (or of the cell at BOUNDARYRES containing it) after its H3 index
+///
+fn h3scan_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.SCAN key cursor [MATCH pattern] [COUNT count] \
+        [WITHBOUNDARY [BOUNDARYRES res]]";
+    if args.len() < 3 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let cursor = match args.next_i64() {
+        Ok(c) => c,
+        Err(_err) => return Err(RedisError::Str("invalid cursor"))
+    };
+    let mut match_pattern: Option<String> = None;
+    let mut count: Option<i64> = None;
+    let mut withboundary = false;
+    let mut boundaryres: Option<u8> = None;
+
+    while let Ok(arg) = args.next_string() {
+        match arg.to_uppercase().as_str() {
+            "MATCH" => match_pattern = Some(args.next_string()?),
+            "COUNT" => count = Some(args.next_i64()?),
+            "WITHBOUNDARY" => withboundary = true,
+            "BOUNDARYRES" => boundaryres = Some(next_res_arg(&mut args)? as u8),
+            _ => {
+                return Err(RedisError::Str(syntax_err_msg));
+            }
+        }
+    }
+    let boundary = boundary_option(withboundary, boundaryres)?;
+
+    let mut newargs: Vec<String> = vec![key, cursor.to_string()];
+    if match_pattern.is_some() {
+        newargs.push(String::from("match"));
+        newargs.push(match_pattern.unwrap());
     }
     if count.is_some() {
         newargs.push(String::from("count"));
@@ -507,7 +1444,11 @@ fn h3scan_command(ctx: &Context, args: Vec<String>) -> RedisResult {
 
                                 match H3Index::new(h3ll) {
                                     Ok(h3idx) => {
-                                        elems_with_indices.push(h3idx.to_string().into())
+                                        elems_with_indices.push(h3idx.to_string().into());
+                                        if let Some(res) = boundary {
+                                            let boundary = member_boundary(&h3idx, res)?;
+                                            elems_with_indices.push(boundary_reply(&boundary));
+                                        }
                                     },
                                     Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
                                 }
@@ -527,7 +1468,6 @@ fn h3scan_command(ctx: &Context, args: Vec<String>) -> RedisResult {
             // this means an entry wasn't found for the elem, ignoring for now
             RedisValue::Null => Ok(RedisValue::Null),
             _ => {
-                println!("v: {:?}", &v);
                 return Err(RedisError::Str("Unexpected type (not Array or Null)"))
             }
         },
@@ -599,7 +1539,7 @@ fn h3dist_command(ctx: &Context, args: Vec<String>) -> RedisResult {
 ///
 /// H3.REMBYINDEX key h3idx1 ... [h3idxN]
 ///
-/// remove elements that match a given H3 index
+/// remove elements that match a given H3 index, along with their histories (see record_positions)
 ///
 fn h3rembyindex_command(ctx: &Context, args: Vec<String>) -> RedisResult {
     let syntax_err_msg = "syntax error. Try H3.REMBYINDEX key h3idx1 ... [h3idxN]";
@@ -637,7 +1577,6 @@ fn h3rembyindex_command(ctx: &Context, args: Vec<String>) -> RedisResult {
                                         zremargs.push(name.to_owned());
                                     },
                                     _ => {
-                                        println!("v: {:?}", &v);
                                         return Err(RedisError::Str("Unexpected types (not SimpleString)"))
                                     }
                                 }
@@ -646,11 +1585,8 @@ fn h3rembyindex_command(ctx: &Context, args: Vec<String>) -> RedisResult {
                         }
                     },
                     // this means an entry wasn't found for the elem, do nothing for now
-                    RedisValue::Null => {
-                        println!("v: {:?}", v);
-                    },
+                    RedisValue::Null => {},
                     _ => {
-                        println!("v: {:?}", v);
                         return Err(RedisError::Str("Unexpected type (not Array or Null)"))
                     }
                 }
@@ -666,75 +1602,2728 @@ fn h3rembyindex_command(ctx: &Context, args: Vec<String>) -> RedisResult {
         let zremargs = &zremargs[..];
     
         // call zadd with zremargs
-        ctx.call("zrem", zremargs)
+        let removed = ctx.call("zrem", zremargs)?;
+        remove_histories(ctx, &key, &zremargs[1..])?;
+        Ok(removed)
     } else {
         let zero: i64 = 0;
         Ok(zero.into())
     }
 }
 
-/// a translation of the GEORADIUS command
-fn h3radius_command(_ctx: &Context, _args: Vec<String>) -> RedisResult {
-    Err(RedisError::Str("Command not implemented"))
+/// get the string out of a SimpleString or BulkString value
+fn value_to_string(v: RedisValue) -> Result<String, RedisError> {
+    match v {
+        RedisValue::SimpleString(s) => Ok(s),
+        RedisValue::BulkString(s) => Ok(s),
+        _ => {
+            Err(RedisError::Str("Unexpected type (not SimpleString)"))
+        }
+    }
 }
 
-/// a translation of the GEORADIUSBYMEMBER command
-fn h3radiusbyindex_command(_ctx: &Context, _args: Vec<String>) -> RedisResult {
-    Err(RedisError::Str("Command not implemented"))
+///
+/// get_cell_members_with_indices
+///
+/// Like get_cell_members, but returns (elem, H3Index as u64) pairs for all elems whose indices
+/// are the given H3 cell or its descendants, for commands that need to process members further
+///
+fn get_cell_members_with_indices(ctx: &Context, key: &String, h3ll: u64) -> Result<Vec<(String, u64)>, RedisError> {
+    let (min_key, max_key) = index_descendant_range(h3ll);
+    get_range_members_with_indices(ctx, key, min_key, max_key)
 }
 
-/// a translation of the GEOSEARCH command
-fn h3search_command(_ctx: &Context, _args: Vec<String>) -> RedisResult {
-    Err(RedisError::Str("Command not implemented"))
+/// get (elem, H3Index as u64) pairs for all elems whose indices are in the range of sort keys
+/// (see index_sort_key) min_h3ll..=max_h3ll
+fn get_range_members_with_indices(ctx: &Context, key: &String, min_h3ll: u64,
+                                  max_h3ll: u64) -> Result<Vec<(String, u64)>, RedisError> {
+    let min_score = format!("{}", h3ll_to_score(min_h3ll));
+    let max_score = format!("{}", h3ll_to_score(max_h3ll));
+    get_score_range_members_with_indices(ctx, key, &min_score, &max_score)
 }
 
-/// a translation of the GEOSEARCHSTORE command
-fn h3searchstore_command(_ctx: &Context, _args: Vec<String>) -> RedisResult {
-    Err(RedisError::Str("Command not implemented"))
+/// get (elem, H3Index as u64) pairs for all elems with scores in min_score..=max_score (which
+/// can be anything ZRANGEBYSCORE accepts, e.g. -inf and +inf)
+fn get_score_range_members_with_indices(ctx: &Context, key: &String, min_score: &str,
+                                        max_score: &str) -> Result<Vec<(String, u64)>, RedisError> {
+    match ctx.call("zrangebyscore", &[key, min_score, max_score, "withscores"]) {
+        Ok(RedisValue::Array(elems)) => {
+            let mut members: Vec<(String, u64)> = Vec::with_capacity(elems.len() / 2);
+            let mut elems = elems.into_iter();
+            while let (Some(elem), Some(score)) = (elems.next(), elems.next()) {
+                let name = value_to_string(elem)?;
+                let score: f64 = match value_to_string(score)?.parse::<f64>() {
+                    Ok(score) => score,
+                    Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+                };
+                members.push((name, score_to_h3ll(score)));
+            }
+            Ok(members)
+        },
+        // this means the key doesn't exist
+        Ok(RedisValue::Null) => Ok(vec![]),
+        Ok(_) => {
+            Err(RedisError::Str("Unexpected type (not Array or Null)"))
+        },
+        Err(err) => Err(err)
+    }
 }
 
-//////////////////////////////////////////////////////
+#[derive(PartialEq)]
+enum SortOrder {
+    None,
+    Asc,
+    Desc,
+}
 
-// pub extern "C" fn init(_raw_ctx: *mut rawmod::RedisModuleCtx) -> c_int {
-//     0
-// }
+/// the options shared by the radius/search commands
+struct SearchOptions {
+    withdist: bool,
+    withcoord: bool,
+    withindices: bool,
+    count: Option<usize>,
+    any: bool,
+    sort: SortOrder,
+    storedist: bool,
+    format: ReplyFormat,
+    withboundary: bool,
+    boundaryres: Option<u8>,
+}
 
-redis_module! {
-    name: "h3",
-    version: 1,
-    data_types: [],
-    // init: init,
-    commands: [
-        ["h3.status", h3status_command, "", 0, 0, 0],
-        ["h3.add", h3add_command, "write deny-oom", 1, 1, 1],
-        ["h3.addbyindex", h3addbyindex_command, "write deny-oom", 1, 1, 1],
-        ["h3.index", h3index_command, "readonly", 1, 1, 1],
-        ["h3.pos", h3pos_command, "readonly", 1, 1, 1],
-        ["h3.cell", h3cell_command, "readonly", 1, 1, 1],
-        ["h3.count", h3count_command, "readonly", 1, 1, 1],
-        ["h3.dist", h3dist_command, "readonly", 1, 1, 1],
-        ["h3.rembyindex", h3rembyindex_command, "write", 1, 1, 1],
-        ["h3.radius", h3radius_command, "readonly", 1, 1, 1],
-        ["h3.radiusbyindex", h3radiusbyindex_command, "readonly", 1, 1, 1],
-        ["h3.scan", h3scan_command, "readonly", 1, 1, 1],
-        ["h3.search", h3search_command, "readonly", 1, 1, 1],
-        ["h3.searchstore", h3searchstore_command, "readonly", 1, 1, 1],
-    ],
+impl SearchOptions {
+    fn new() -> SearchOptions {
+        SearchOptions {
+            withdist: false,
+            withcoord: false,
+            withindices: false,
+            count: None,
+            any: false,
+            sort: SortOrder::None,
+            storedist: false,
+            format: ReplyFormat::Array,
+            withboundary: false,
+            boundaryres: None,
+        }
+    }
+
+    /// try to parse arg (and any values following it) as a search option, returns false if
+    /// arg isn't a search option so the caller can handle it
+    fn parse_arg<I: Iterator<Item = String>>(&mut self, arg: &str, args: &mut I) -> Result<bool, RedisError> {
+        match arg.to_uppercase().as_str() {
+            "WITHDIST" => self.withdist = true,
+            "WITHCOORD" => self.withcoord = true,
+            "WITHINDICES" => self.withindices = true,
+            "ANY" => self.any = true,
+            "ASC" => self.sort = SortOrder::Asc,
+            "DESC" => self.sort = SortOrder::Desc,
+            "FORMAT" => self.format = next_format_arg(args)?,
+            "WITHBOUNDARY" => self.withboundary = true,
+            "BOUNDARYRES" => self.boundaryres = Some(next_res_arg(args)? as u8),
+            "COUNT" => {
+                let count = args.next_i64()?;
+                if count <= 0 {
+                    return Err(RedisError::Str("COUNT must be > 0"));
+                }
+                self.count = Some(count as usize);
+            },
+            _ => return Ok(false)
+        }
+        Ok(true)
+    }
+
+    /// check option combinations once all args have been parsed
+    fn validate(&mut self) -> Result<(), RedisError> {
+        if self.any && self.count.is_none() {
+            return Err(RedisError::Str("the ANY argument requires COUNT argument"));
+        }
+        if self.withboundary && self.format != ReplyFormat::GeoJson {
+            return Err(RedisError::Str("WITHBOUNDARY requires FORMAT GEOJSON"));
+        }
+        boundary_option(self.withboundary, self.boundaryres)?;
+        // like GEOSEARCH, COUNT without ANY implies sorting by distance
+        if self.count.is_some() && !self.any && self.sort == SortOrder::None {
+            self.sort = SortOrder::Asc;
+        }
+        Ok(())
+    }
+
+    /// the number of matches at which a search can stop early (only with ANY)
+    fn stop_at(&self) -> Option<usize> {
+        if self.any { self.count } else { None }
+    }
 }
 
-//////////////////////////////////////////////////////
+/// a member found by a radius/search command
+struct SearchResult {
+    name: String,
+    h3idx: H3Index,
+    lng: f64,
+    lat: f64,
+    dist: f64,
+}
 
-#[cfg(test)]
-mod tests {
-    use redis_module::RedisValue;
+/// the area searched by a radius/search command, distances are in meters
+enum SearchShape {
+    Radius(f64),
+    Box(f64, f64),
+    Cell(H3Index),
+    KRing(H3Index, i32),
+}
 
-    use super::*;
+impl SearchShape {
+    /// the cells whose members are candidates for a search of this shape around lng/lat
+    fn cover_cells(&self, lng: f64, lat: f64) -> Result<Vec<H3Index>, RedisError> {
+        let radius = match self {
+            SearchShape::Radius(radius) => *radius,
+            SearchShape::Box(width, height) => (width * width + height * height).sqrt() / 2.0,
+            SearchShape::Cell(h3idx) => return Ok(vec![h3idx.clone()]),
+            SearchShape::KRing(h3idx, k) => return Ok(h3idx.k_ring(*k)),
+        };
 
-    fn run_status() -> RedisResult {
-        h3status_command(
-            &Context::dummy(),
-            vec![],
-        )
+        let (res, k) = radius_to_kring(radius);
+        match GeoCoord::new(lat, lng).to_h3(res) {
+            Ok(center) => Ok(center.k_ring(k)),
+            Err(_err) => Err(RedisError::Str("Invalid lng or lat value"))
+        }
+    }
+
+    /// get the distance from lng/lat to a candidate position if it is within the shape
+    fn distance_if_within(&self, lng: f64, lat: f64, coord: &GeoCoord) -> Option<f64> {
+        match self {
+            SearchShape::Radius(radius) => {
+                let dist = geohash_get_distance(lng, lat, coord.lon, coord.lat);
+                if dist <= *radius { Some(dist) } else { None }
+            },
+            SearchShape::Box(width, height) => {
+                geohash_get_distance_if_in_rectangle(*width, *height, lng, lat, coord.lon, coord.lat)
+            },
+            // cell candidates are exactly the members of the cells
+            SearchShape::Cell(_) | SearchShape::KRing(_, _) => {
+                Some(geohash_get_distance(lng, lat, coord.lon, coord.lat))
+            }
+        }
+    }
+}
+
+///
+/// get_shape_members
+///
+/// Covers the shape around lng/lat with cells, gets the members of the cells (with one query per
+/// range of adjacent cells, see merge_cell_ranges) and keeps those whose centroid is within the
+/// shape. Members at a coarser resolution than the cover cells aren't in their ranges, so they
+/// aren't found (see H3.CELL INTERSECTS and H3.CONTAINS for those).
+///
+fn get_shape_members(ctx: &Context, key: &String, lng: f64, lat: f64, shape: &SearchShape,
+                     stop_at: Option<usize>) -> Result<Vec<SearchResult>, RedisError> {
+    let cells: Vec<u64> = shape.cover_cells(lng, lat)?.iter().map(h3_to_h3ll).collect();
+    let mut results: Vec<SearchResult> = Vec::new();
+    for (min_h3ll, max_h3ll) in merge_cell_ranges(&cells) {
+        for (name, h3ll) in get_range_members_with_indices(ctx, key, min_h3ll, max_h3ll)? {
+            let h3idx = match H3Index::new(h3ll) {
+                Ok(h3idx) => h3idx,
+                Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+            };
+            let coord: GeoCoord = h3idx.to_geo();
+            if let Some(dist) = shape.distance_if_within(lng, lat, &coord) {
+                results.push(SearchResult { name, h3idx, lng: coord.lon, lat: coord.lat, dist });
+                if stop_at == Some(results.len()) {
+                    return Ok(results);
+                }
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// sort and limit search results according to opts
+fn sort_and_limit(mut results: Vec<SearchResult>, opts: &SearchOptions) -> Vec<SearchResult> {
+    match opts.sort {
+        SortOrder::Asc => results.sort_by(|a, b| a.dist.partial_cmp(&b.dist).unwrap_or(Ordering::Equal)),
+        SortOrder::Desc => results.sort_by(|a, b| b.dist.partial_cmp(&a.dist).unwrap_or(Ordering::Equal)),
+        SortOrder::None => (),
+    }
+    if let Some(count) = opts.count {
+        results.truncate(count);
+    }
+    results
+}
+
+/// sort and limit search results according to opts and convert them to a reply, distances
+/// are returned in the unit that to_meter converts from. With FORMAT GEOJSON the reply is a
+/// FeatureCollection, which always has the H3 indices and distances (the WITH* options for
+/// arrays are ignored).
+fn search_results_reply(results: Vec<SearchResult>, opts: &SearchOptions, to_meter: f64) -> RedisResult {
+    let results = sort_and_limit(results, opts);
+
+    if opts.format == ReplyFormat::GeoJson {
+        let boundary = boundary_option(opts.withboundary, opts.boundaryres)?;
+        let mut features: Vec<String> = Vec::with_capacity(results.len());
+        for r in results {
+            features.push(member_to_geojson(&r.name, &r.h3idx, Some(r.dist / to_meter), boundary)?);
+        }
+        return Ok(geojson_feature_collection(&features).into());
+    }
+
+    let reply: Vec<RedisValue> = results.into_iter().map(|r| -> RedisValue {
+        if !opts.withdist && !opts.withcoord && !opts.withindices {
+            return r.name.into();
+        }
+        let mut item: Vec<RedisValue> = vec![r.name.into()];
+        if opts.withdist {
+            item.push(format!("{:.4}", r.dist / to_meter).into());
+        }
+        if opts.withindices {
+            item.push(r.h3idx.to_string().into());
+        }
+        if opts.withcoord {
+            item.push(vec![r.lng.to_string(), r.lat.to_string()].into());
+        }
+        item.into()
+    }).collect();
+    Ok(reply.into())
+}
+
+///
+/// H3.RADIUS key lng lat radius m|km|ft|mi [WITHDIST] [WITHCOORD] [WITHINDICES] [COUNT count [ANY]] [ASC|DESC]
+///     [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]
+///
+/// a translation of the GEORADIUS command, returns the elements whose positions are within
+/// radius of lng/lat
+///
+fn h3radius_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.RADIUS key lng lat radius m|km|ft|mi [WITHDIST] \
+        [WITHCOORD] [WITHINDICES] [COUNT count [ANY]] [ASC|DESC] \
+        [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]";
+    if args.len() < 6 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let (lng, lat) = match (args.next_f64(), args.next_f64()) {
+        (Ok(lng), Ok(lat)) => (lng, lat),
+        _ => return Err(RedisError::Str("Invalid lng or lat value"))
+    };
+    let radius = args.next_f64()?;
+    if radius < 0.0 {
+        return Err(RedisError::Str("radius cannot be negative"));
+    }
+    if !radius.is_finite() {
+        return Err(RedisError::Str("radius must be a finite number"));
+    }
+    let to_meter = unit_str_to_conversion(&args.next_string()?)?;
+
+    let mut opts = SearchOptions::new();
+    while let Ok(arg) = args.next_string() {
+        if !opts.parse_arg(&arg, &mut args)? {
+            return Err(RedisError::Str(syntax_err_msg));
+        }
+    }
+    opts.validate()?;
+
+    let shape = SearchShape::Radius(radius * to_meter);
+    let results = get_shape_members(ctx, &key, lng, lat, &shape, opts.stop_at())?;
+    search_results_reply(results, &opts, to_meter)
+}
+
+/// get the H3Index of a single member of key, or an error if it doesn't exist
+fn get_member_h3_index(ctx: &Context, key: &String, member: String) -> Result<H3Index, RedisError> {
+    match get_zscores_as_h3_indices(ctx, key, vec![member])?.pop() {
+        Some(Some(h3idx)) => Ok(h3idx),
+        _ => Err(RedisError::Str("could not decode requested zset member"))
+    }
+}
+
+///
+/// radius_by_index
+///
+/// The shared implementation of H3.RADIUSBYINDEX and H3.RADIUSBYMEMBER, args start with the
+/// radius (the key and center have already been consumed)
+///
+fn radius_by_index<I: Iterator<Item = String>>(ctx: &Context, key: &String, center: &H3Index,
+                                               mut args: I, syntax_err_msg: &'static str) -> RedisResult {
+    let radius = args.next_f64()?;
+    if radius < 0.0 {
+        return Err(RedisError::Str("radius cannot be negative"));
+    }
+    if !radius.is_finite() {
+        return Err(RedisError::Str("radius must be a finite number"));
+    }
+    let to_meter = unit_str_to_conversion(&args.next_string()?)?;
+
+    let mut opts = SearchOptions::new();
+    while let Ok(arg) = args.next_string() {
+        if !opts.parse_arg(&arg, &mut args)? {
+            return Err(RedisError::Str(syntax_err_msg));
+        }
+    }
+    opts.validate()?;
+
+    let coord: GeoCoord = center.to_geo();
+    let shape = SearchShape::Radius(radius * to_meter);
+    let results = get_shape_members(ctx, key, coord.lon, coord.lat, &shape, opts.stop_at())?;
+    search_results_reply(results, &opts, to_meter)
+}
+
+///
+/// H3.RADIUSBYINDEX key h3idx|BYMEMBER member radius m|km|ft|mi [WITHDIST] [WITHCOORD] [WITHINDICES] [COUNT count [ANY]] [ASC|DESC]
+///     [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]
+///
+/// like H3.RADIUS, but the center is the centroid of the given H3 index (any resolution), or of
+/// the index of an existing member when BYMEMBER is given
+///
+fn h3radiusbyindex_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.RADIUSBYINDEX key h3idx|BYMEMBER member radius \
+        m|km|ft|mi [WITHDIST] [WITHCOORD] [WITHINDICES] [COUNT count [ANY]] [ASC|DESC] \
+        [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]";
+    if args.len() < 5 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let h3key = args.next_string()?;
+
+    let center = if h3key.to_uppercase() == "BYMEMBER" {
+        get_member_h3_index(ctx, &key, args.next_string()?)?
+    } else {
+        match str_to_h3(&h3key) {
+            Ok(h3idx) => h3idx,
+            Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+        }
+    };
+
+    radius_by_index(ctx, &key, &center, args, syntax_err_msg)
+}
+
+///
+/// H3.RADIUSBYMEMBER key member radius m|km|ft|mi [WITHDIST] [WITHCOORD] [WITHINDICES] [COUNT count [ANY]] [ASC|DESC]
+///     [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]
+///
+/// a translation of the GEORADIUSBYMEMBER command, same as H3.RADIUSBYINDEX key BYMEMBER member
+///
+fn h3radiusbymember_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.RADIUSBYMEMBER key member radius m|km|ft|mi \
+        [WITHDIST] [WITHCOORD] [WITHINDICES] [COUNT count [ANY]] [ASC|DESC] \
+        [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]";
+    if args.len() < 5 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let center = get_member_h3_index(ctx, &key, args.next_string()?)?;
+
+    radius_by_index(ctx, &key, &center, args, syntax_err_msg)
+}
+
+/// a parsed H3.SEARCH query
+struct SearchQuery {
+    lng: f64,
+    lat: f64,
+    shape: SearchShape,
+    to_meter: f64,
+    opts: SearchOptions,
+}
+
+///
+/// parse_search_query
+///
+/// Parses the H3.SEARCH arguments that follow the key, args that aren't part of the H3.SEARCH
+/// grammar are a syntax error (STOREDIST is only allowed when store is true)
+///
+fn parse_search_query<I: Iterator<Item = String>>(ctx: &Context, key: &String, mut args: I, store: bool,
+                                                  syntax_err_msg: &'static str) -> Result<SearchQuery, RedisError> {
+    let mut from: Option<(f64, f64)> = None;
+    let mut shape: Option<SearchShape> = None;
+    let mut to_meter: f64 = 1.0;
+    let mut opts = SearchOptions::new();
+
+    while let Ok(arg) = args.next_string() {
+        match arg.to_uppercase().as_str() {
+            "FROMMEMBER" | "FROMLONLAT" => {
+                if from.is_some() {
+                    return Err(RedisError::Str("exactly one of FROMMEMBER or FROMLONLAT can be specified"));
+                }
+                if arg.to_uppercase() == "FROMMEMBER" {
+                    let coord: GeoCoord = get_member_h3_index(ctx, key, args.next_string()?)?.to_geo();
+                    from = Some((coord.lon, coord.lat));
+                } else {
+                    match (args.next_f64(), args.next_f64()) {
+                        (Ok(lng), Ok(lat)) => from = Some((lng, lat)),
+                        _ => return Err(RedisError::Str("Invalid lng or lat value"))
+                    }
+                }
+            },
+            "BYRADIUS" | "BYBOX" | "BYCELL" | "BYKRING" => {
+                if shape.is_some() {
+                    return Err(RedisError::Str(
+                        "exactly one of BYRADIUS, BYBOX, BYCELL or BYKRING can be specified"
+                    ));
+                }
+                shape = Some(match arg.to_uppercase().as_str() {
+                    "BYRADIUS" => {
+                        let radius = args.next_f64()?;
+                        if radius < 0.0 {
+                            return Err(RedisError::Str("radius cannot be negative"));
+                        }
+                        if !radius.is_finite() {
+                            return Err(RedisError::Str("radius must be a finite number"));
+                        }
+                        to_meter = unit_str_to_conversion(&args.next_string()?)?;
+                        SearchShape::Radius(radius * to_meter)
+                    },
+                    "BYBOX" => {
+                        let width = args.next_f64()?;
+                        let height = args.next_f64()?;
+                        if width < 0.0 || height < 0.0 {
+                            return Err(RedisError::Str("height or width cannot be negative"));
+                        }
+                        if !width.is_finite() || !height.is_finite() {
+                            return Err(RedisError::Str("height and width must be finite numbers"));
+                        }
+                        to_meter = unit_str_to_conversion(&args.next_string()?)?;
+                        SearchShape::Box(width * to_meter, height * to_meter)
+                    },
+                    _ => {
+                        let h3idx = match str_to_h3(&args.next_string()?) {
+                            Ok(h3idx) => h3idx,
+                            Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+                        };
+                        if arg.to_uppercase() == "BYCELL" {
+                            SearchShape::Cell(h3idx)
+                        } else {
+                            let k = args.next_i64()?;
+                            if k < 0 || k > MAX_SEARCH_K {
+                                return Err(RedisError::Str("Invalid k (must be 0-100)"));
+                            }
+                            SearchShape::KRing(h3idx, k as i32)
+                        }
+                    }
+                });
+            },
+            "STOREDIST" if store => opts.storedist = true,
+            _ => {
+                if !opts.parse_arg(&arg, &mut args)? {
+                    return Err(RedisError::Str(syntax_err_msg));
+                }
+            }
+        }
+    }
+    opts.validate()?;
+
+    let shape = match shape {
+        Some(shape) => shape,
+        None => return Err(RedisError::Str(
+            "exactly one of BYRADIUS, BYBOX, BYCELL or BYKRING must be provided"
+        ))
+    };
+
+    // the cell shapes don't need a FROM, distances are then from the cell's centroid
+    let (lng, lat) = match (from, &shape) {
+        (Some(from), _) => from,
+        (None, SearchShape::Cell(h3idx)) | (None, SearchShape::KRing(h3idx, _)) => {
+            let coord: GeoCoord = h3idx.to_geo();
+            (coord.lon, coord.lat)
+        },
+        (None, _) => return Err(RedisError::Str(
+            "exactly one of FROMMEMBER or FROMLONLAT must be provided"
+        ))
+    };
+
+    Ok(SearchQuery { lng, lat, shape, to_meter, opts })
+}
+
+///
+/// H3.SEARCH key [FROMMEMBER member] [FROMLONLAT lng lat] [BYRADIUS radius m|km|ft|mi]
+///     [BYBOX width height m|km|ft|mi] [BYCELL h3idx] [BYKRING h3idx k] [ASC|DESC]
+///     [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHINDICES]
+///     [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]
+///
+/// a translation of the GEOSEARCH command, with the additional BYCELL and BYKRING shapes that
+/// search the members of a cell or of the k-ring around it (FROM is optional for these, and
+/// distances are in meters from the FROM position or the cell's centroid), k can be 0-100
+///
+fn h3search_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.SEARCH key [FROMMEMBER member] [FROMLONLAT lng lat] \
+        [BYRADIUS radius m|km|ft|mi] [BYBOX width height m|km|ft|mi] [BYCELL h3idx] \
+        [BYKRING h3idx k] [ASC|DESC] [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHINDICES] \
+        [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]";
+    if args.len() < 4 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let query = parse_search_query(ctx, &key, args, false, syntax_err_msg)?;
+
+    let results = get_shape_members(ctx, &key, query.lng, query.lat, &query.shape,
+                                    query.opts.stop_at())?;
+    search_results_reply(results, &query.opts, query.to_meter)
+}
+
+///
+/// H3.SEARCHSTORE destination source [FROMMEMBER member] [FROMLONLAT lng lat]
+///     [BYRADIUS radius m|km|ft|mi] [BYBOX width height m|km|ft|mi] [BYCELL h3idx] [BYKRING h3idx k]
+///     [ASC|DESC] [COUNT count [ANY]] [STOREDIST]
+///
+/// a translation of the GEOSEARCHSTORE command, stores the results of the H3.SEARCH in the
+/// destination zset with their H3 index scores (so it is also an H3 key), or with their
+/// distances as scores if STOREDIST is given, and returns the number of elements stored
+///
+fn h3searchstore_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.SEARCHSTORE destination source [FROMMEMBER member] \
+        [FROMLONLAT lng lat] [BYRADIUS radius m|km|ft|mi] [BYBOX width height m|km|ft|mi] \
+        [BYCELL h3idx] [BYKRING h3idx k] [ASC|DESC] [COUNT count [ANY]] [STOREDIST]";
+    if args.len() < 5 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let dest = args.next_string()?;
+    let key = args.next_string()?;
+    let query = parse_search_query(ctx, &key, args, true, syntax_err_msg)?;
+    if query.opts.withdist || query.opts.withcoord || query.opts.withindices {
+        return Err(RedisError::Str(
+            "WITHDIST, WITHINDICES and WITHCOORD options can't be used with H3.SEARCHSTORE"
+        ));
+    }
+    if query.opts.format != ReplyFormat::Array {
+        return Err(RedisError::Str("FORMAT can't be used with H3.SEARCHSTORE"));
+    }
+
+    let results = get_shape_members(ctx, &key, query.lng, query.lat, &query.shape,
+                                    query.opts.stop_at())?;
+    let results = sort_and_limit(results, &query.opts);
+
+    // like GEOSEARCHSTORE, the destination is overwritten (and removed if there are no results),
+    // the command is replicated as is since the DEL and ZADD calls aren't
+    ctx.call("del", &[&dest])?;
+    ctx.replicate_verbatim();
+    if results.is_empty() {
+        let zero: i64 = 0;
+        return Ok(zero.into());
+    }
+
+    let mut zaddargs: Vec<String> = Vec::with_capacity(1 + results.len() * 2);
+    zaddargs.push(dest);
+    for r in results {
+        let score = if query.opts.storedist {
+            r.dist / query.to_meter
+        } else {
+            h3ll_to_score(h3_to_h3ll(&r.h3idx))
+        };
+        zaddargs.push(format!("{}", score));
+        zaddargs.push(r.name);
+    }
+
+    let zaddargs: Vec<&str> = zaddargs.iter().map(|s| {
+        s.as_str()
+    }).collect();
+    let zaddargs = &zaddargs[..];
+
+    ctx.call("zadd", zaddargs)
+}
+
+/// parse a resolution arg
+fn next_res_arg<I: Iterator<Item = String>>(args: &mut I) -> Result<i32, RedisError> {
+    let res = args.next_i64()?;
+    if res < MIN_RESOLUTION as i64 || res > MAX_RESOLUTION as i64 {
+        return Err(RedisError::Str("Invalid resolution (must be 0-15)"));
+    }
+    Ok(res as i32)
+}
+
+/// polygon args: lng/lat lists (with HOLE) and GEOJSON or WKT geometries
+struct PolygonArgs {
+    rings: Vec<Vec<(f64, f64)>>,
+    polygons: Vec<Polygon>,
+}
+
+impl PolygonArgs {
+    fn new() -> PolygonArgs {
+        PolygonArgs {
+            rings: vec![vec![]],
+            polygons: vec![],
+        }
+    }
+
+    /// try to parse arg as part of a polygon: a lng (followed by a lat arg) is added to the
+    /// current ring, HOLE starts a new ring and GEOJSON or WKT is followed by a geometry with one
+    /// or more polygons, returns false if arg is none of these
+    fn parse_arg<I: Iterator<Item = String>>(&mut self, arg: &str, args: &mut I) -> Result<bool, RedisError> {
+        match arg.to_uppercase().as_str() {
+            "HOLE" => self.rings.push(vec![]),
+            "GEOJSON" => self.polygons.extend(parse_geojson(&args.next_string()?).map_err(RedisError::String)?),
+            "WKT" => self.polygons.extend(parse_wkt(&args.next_string()?).map_err(RedisError::String)?),
+            _ => match arg.parse::<f64>() {
+                Ok(lng) => match args.next_f64() {
+                    Ok(lat) if valid_position(lng, lat) => self.rings.last_mut().unwrap().push((lng, lat)),
+                    _ => return Err(RedisError::Str("Invalid lng or lat value"))
+                },
+                Err(_err) => return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+
+    /// get the parsed polygons, the polygon given as lng/lat lists (if any) being the last
+    fn into_polygons(mut self) -> Result<Vec<Polygon>, RedisError> {
+        if self.rings.len() > 1 || !self.rings[0].is_empty() {
+            if self.rings.iter().any(|ring| distinct_positions(ring) < 3) {
+                return Err(RedisError::Str("each polygon ring needs at least 3 distinct lng/lat vertices"));
+            }
+            let outer = self.rings.remove(0);
+            self.polygons.push(Polygon { outer, holes: self.rings });
+        }
+        if self.polygons.is_empty() {
+            return Err(RedisError::Str("a polygon (lng/lat vertices, GEOJSON or WKT) is required"));
+        }
+        Ok(self.polygons)
+    }
+}
+
+/// get the default resolution to fill polygons with (see polyfill_resolution)
+fn polygons_resolution(polygons: &[Polygon]) -> i32 {
+    let bbox_sizes: Vec<(f64, f64)> = polygons.iter().map(|polygon| polygon.bbox_size()).collect();
+    polyfill_resolution(&bbox_sizes)
+}
+
+///
+/// get_polygon_cells
+///
+/// Gets the compacted set of cells to query for the members of polygons at resolution res, when
+/// exact is true the cells along the polygons' borders (the edges of their outer rings and holes,
+/// see ring_cells) and their neighbors are included too. A cell that no border passes through is
+/// either entirely inside a polygon, so it's filled, or entirely outside, so no members inside
+/// the polygons are missed by the post-filter. Polygons that would need more than
+/// MAX_RES_POLYFILL_CELLS cells at resolution res (see polyfill_cell_estimate) are rejected
+/// before they're filled.
+///
+fn get_polygon_cells(polygons: &[Polygon], res: i32, exact: bool) -> Result<Vec<u64>, RedisError> {
+    let bbox_sizes: Vec<(f64, f64)> = polygons.iter().map(|polygon| polygon.bbox_size()).collect();
+    let estimate = polyfill_cell_estimate(&bbox_sizes, res);
+    if estimate.is_nan() || estimate > MAX_RES_POLYFILL_CELLS {
+        return Err(RedisError::Str("too many cells at this resolution (more than 100000), use a lower RES"));
+    }
+
+    let mut cells: HashSet<u64> = HashSet::new();
+    for polygon in polygons {
+        cells.extend(polyfill(polygon, res).iter().map(h3_to_h3ll));
+
+        if exact {
+            for ring in std::iter::once(&polygon.outer).chain(polygon.holes.iter()) {
+                let border = match ring_cells(ring, res) {
+                    Ok(border) => border,
+                    Err(_err) => return Err(RedisError::Str("Invalid lng or lat value"))
+                };
+                for cell in border {
+                    cells.extend(cell.k_ring(1).iter().map(h3_to_h3ll));
+                }
+            }
+        }
+    }
+
+    let cells: Vec<u64> = cells.into_iter().collect();
+    Ok(compact_cells(&cells))
+}
+
+///
+/// H3.POLYGON key lng1 lat1 ... lngN latN [HOLE lng1 lat1 ... lngN latN] [RES res] [EXACT]
+///     [WITHINDICES] [COUNT count] [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]
+/// H3.POLYGON key GEOJSON json|WKT wkt [RES res] [EXACT] [WITHINDICES] [COUNT count]
+///     [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]
+///
+/// Returns an array of the elements in the zset that are inside the polygon. The polygon is
+/// filled with cells at resolution res (by default the finest resolution that doesn't need an
+/// unreasonable number of cells), which are compacted and queried like H3.CELL. Without EXACT
+/// the cells are the cells whose centroids are inside the polygon, with EXACT each element's
+/// position is checked against the polygon. GEOJSON (a Polygon or MultiPolygon, possibly in a
+/// Feature or FeatureCollection) and WKT (a POLYGON or MULTIPOLYGON) can give more than one
+/// polygon, the elements inside any of them are returned. A res at which the polygons would need
+/// more than 100000 cells is an error.
+///
+fn h3polygon_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.POLYGON key lng1 lat1 ... lngN latN \
+        [HOLE lng1 lat1 ... lngN latN]|GEOJSON json|WKT wkt [RES res] [EXACT] [WITHINDICES] [COUNT count] \
+        [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]";
+    if args.len() < 4 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+
+    let mut polygon_args = PolygonArgs::new();
+    let mut res: Option<i32> = None;
+    let mut exact = false;
+    let mut withindices = false;
+    let mut format = ReplyFormat::Array;
+    let mut withboundary = false;
+    let mut boundaryres: Option<u8> = None;
+    let mut count: Option<usize> = None;
+
+    while let Ok(arg) = args.next_string() {
+        match arg.to_uppercase().as_str() {
+            "RES" => res = Some(next_res_arg(&mut args)?),
+            "EXACT" => exact = true,
+            "WITHINDICES" => withindices = true,
+            "FORMAT" => format = next_format_arg(&mut args)?,
+            "WITHBOUNDARY" => withboundary = true,
+            "BOUNDARYRES" => boundaryres = Some(next_res_arg(&mut args)? as u8),
+            "COUNT" => {
+                let c = args.next_i64()?;
+                if c <= 0 {
+                    return Err(RedisError::Str("COUNT must be > 0"));
+                }
+                count = Some(c as usize);
+            },
+            _ => {
+                if !polygon_args.parse_arg(&arg, &mut args)? {
+                    return Err(RedisError::Str(syntax_err_msg));
+                }
+            }
+        }
+    }
+
+    let polygons = polygon_args.into_polygons()?;
+    let res = res.unwrap_or_else(|| polygons_resolution(&polygons));
+    let boundary = boundary_option(withboundary, boundaryres)?;
+    let mut reply = MembersReply::new(format, withindices, boundary)?;
+    let cells = get_polygon_cells(&polygons, res, exact)?;
+
+    let mut found: usize = 0;
+    'cells: for cell in cells {
+        for (name, h3ll) in get_cell_members_with_indices(ctx, &key, cell)? {
+            if exact {
+                let coord: GeoCoord = match H3Index::new(h3ll) {
+                    Ok(h3idx) => h3idx.to_geo(),
+                    Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+                };
+                if !polygons.iter().any(|polygon| polygon.contains(coord.lon, coord.lat)) {
+                    continue;
+                }
+            }
+            reply.push(name, h3ll)?;
+            found += 1;
+            if count == Some(found) {
+                break 'cells;
+            }
+        }
+    }
+
+    Ok(reply.into_reply())
+}
+
+///
+/// H3.SETADD key h3idx member [h3idx2 member2 ... h3idxN memberN]
+///
+/// adds (or moves) members to an h3idx-set, the native H3 data type which keys members by their
+/// full H3 indices, so indices of any resolution can be added. Returns the number of new members.
+///
+fn h3setadd_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() < 4 || args.len() % 2 != 0 {
+        return Err(RedisError::Str(
+            "syntax error. Try H3.SETADD key [h3idx1] [member1] [h3idx2] [member2] ... "
+        ));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+
+    // validate all indices before changing anything
+    let mut entries: Vec<(u64, String)> = Vec::with_capacity(args.len() / 2);
+    while args.len() > 0 {
+        let h3key = args.next_string()?;
+        let member = args.next_string()?;
+        match str_to_h3(&h3key) {
+            Ok(h3idx) => entries.push((h3_to_h3ll(&h3idx), member)),
+            Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+        }
+    }
+
+    let redis_key = ctx.open_key_writable(&key);
+    let added = match redis_key.get_value::<H3Set>(&H3SET_TYPE)? {
+        Some(set) => entries.into_iter().filter(|(h3ll, member)| set.insert(member.clone(), *h3ll)).count(),
+        None => {
+            let mut set = H3Set::new();
+            let added = entries.into_iter().filter(|(h3ll, member)| set.insert(member.clone(), *h3ll)).count();
+            redis_key.set_value(&H3SET_TYPE, set)?;
+            added
+        }
+    };
+
+    ctx.replicate_verbatim();
+    Ok((added as i64).into())
+}
+
+///
+/// H3.SETREM key member1 ... [memberN]
+///
+/// removes members from an h3idx-set, returns the number of members removed
+///
+fn h3setrem_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::Str("syntax error. Try H3.SETREM key member1 ... [memberN]"));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+
+    let redis_key = ctx.open_key_writable(&key);
+    let removed = match redis_key.get_value::<H3Set>(&H3SET_TYPE)? {
+        Some(set) => {
+            let removed = args.filter(|member| set.remove(member).is_some()).count();
+            if set.len() == 0 {
+                redis_key.delete()?;
+            }
+            removed
+        },
+        None => 0
+    };
+
+    if removed > 0 {
+        ctx.replicate_verbatim();
+    }
+    Ok((removed as i64).into())
+}
+
+///
+/// H3.SETINDEX key member1 ... [memberN]
+///
+/// like H3.INDEX, for an h3idx-set
+///
+fn h3setindex_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::Str("syntax error. Try H3.SETINDEX key member1 ... [memberN]"));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+
+    let redis_key = ctx.open_key(&key);
+    let set = redis_key.get_value::<H3Set>(&H3SET_TYPE)?;
+
+    let mut h3indices: Vec<RedisValue> = Vec::with_capacity(args.len());
+    for member in args {
+        match set.as_ref().and_then(|set| set.get(&member)) {
+            Some(h3ll) => match H3Index::new(h3ll) {
+                Ok(h3idx) => h3indices.push(h3idx.to_string().into()),
+                Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+            },
+            None => h3indices.push(RedisValue::Null)
+        }
+    }
+    Ok(h3indices.into())
+}
+
+///
+/// H3.SETCELL key h3idx [WITHINDICES]
+///
+/// like H3.CELL for an h3idx-set, returns the members whose cells are the given cell or are
+/// contained in it (at any resolution)
+///
+fn h3setcell_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.SETCELL key h3idx [WITHINDICES]";
+    if args.len() < 3 || args.len() > 4 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let h3idx = match str_to_h3(&args.next_string()?) {
+        Ok(h3idx) => h3idx,
+        Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+    };
+    let withindices = match args.next_string() {
+        Ok(arg) if arg.to_uppercase() == "WITHINDICES" => true,
+        Ok(_) => return Err(RedisError::Str(syntax_err_msg)),
+        Err(_) => false
+    };
+
+    let redis_key = ctx.open_key(&key);
+    let members = match redis_key.get_value::<H3Set>(&H3SET_TYPE)? {
+        Some(set) => set.cell_members(h3_to_h3ll(&h3idx)),
+        None => vec![]
+    };
+
+    let mut reply: Vec<RedisValue> = Vec::with_capacity(members.len());
+    for (member, h3ll) in members {
+        reply.push(member.into());
+        if withindices {
+            match H3Index::new(h3ll) {
+                Ok(h3idx) => reply.push(h3idx.to_string().into()),
+                Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+            }
+        }
+    }
+    Ok(reply.into())
+}
+
+///
+/// H3.SETCARD key
+///
+/// returns the number of members in an h3idx-set
+///
+fn h3setcard_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() != 2 {
+        return Err(RedisError::Str("syntax error. Try H3.SETCARD key"));
+    }
+
+    let redis_key = ctx.open_key(&args[1]);
+    let len = match redis_key.get_value::<H3Set>(&H3SET_TYPE)? {
+        Some(set) => set.len(),
+        None => 0
+    };
+    Ok((len as i64).into())
+}
+
+///
+/// H3.FROMLONLAT lng lat res
+///
+/// Returns the H3 index of the cell at resolution res containing lng/lat (doesn't use a key)
+///
+fn h3fromlonlat_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() != 4 {
+        return Err(RedisError::Str("syntax error. Try H3.FROMLONLAT lng lat res"));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let (lng, lat) = match (args.next_f64(), args.next_f64()) {
+        (Ok(lng), Ok(lat)) => (lng, lat),
+        _ => return Err(RedisError::Str("Invalid lng or lat value"))
+    };
+    let res = args.next_i64()?;
+    if res < MIN_RESOLUTION as i64 || res > MAX_RESOLUTION as i64 {
+        return Err(RedisError::Str("Invalid resolution (must be 0-15)"));
+    }
+
+    match GeoCoord::new(lat, lng).to_h3(res as i32) {
+        Ok(h3idx) => Ok(h3idx.to_string().into()),
+        Err(_err) => Err(RedisError::Str("Invalid lng or lat value"))
+    }
+}
+
+///
+/// H3.TOLONLAT h3idx
+///
+/// Returns the lng/lat of the centroid of the given H3 index's cell (doesn't use a key)
+///
+fn h3tolonlat_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() != 2 {
+        return Err(RedisError::Str("syntax error. Try H3.TOLONLAT h3idx"));
+    }
+
+    let h3idx = match str_to_h3(&args[1]) {
+        Ok(h3idx) => h3idx,
+        Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+    };
+    let coord = h3idx.to_geo();
+    Ok(vec![coord.lon.to_string(), coord.lat.to_string()].into())
+}
+
+///
+/// H3.BOUNDARY h3idx [GEOJSON|WKT]
+///
+/// Returns the vertices of the boundary of the given H3 index's cell as an array of lng/lat
+/// arrays, or as a GeoJSON Polygon geometry or WKT POLYGON string (doesn't use a key)
+///
+fn h3boundary_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.BOUNDARY h3idx [GEOJSON|WKT]";
+    if args.len() != 2 && args.len() != 3 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let h3idx = match str_to_h3(&args[1]) {
+        Ok(h3idx) => h3idx,
+        Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+    };
+    let boundary = cell_boundary(&h3idx);
+
+    match args.get(2).map(|f| f.to_uppercase()).as_ref().map(|f| f.as_str()) {
+        None => Ok(boundary_reply(&boundary)),
+        Some("GEOJSON") => Ok(ring_to_geojson(&boundary).into()),
+        Some("WKT") => Ok(ring_to_wkt(&boundary).into()),
+        Some(_) => Err(RedisError::Str(syntax_err_msg))
+    }
+}
+
+// the max number of children H3.CHILDREN returns (and the max COUNT)
+const MAX_CHILDREN: u64 = 100000;
+
+/// parse the h3idx and res args shared by the hierarchy commands
+fn h3idx_and_res_args(args: Vec<String>, syntax_err_msg: &'static str) -> Result<(u64, u8, Vec<String>), RedisError> {
+    if args.len() < 3 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let h3idx = match str_to_h3(&args.next_string()?) {
+        Ok(h3idx) => h3idx,
+        Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+    };
+    let res = args.next_i64()?;
+    if res < MIN_RESOLUTION as i64 || res > MAX_RESOLUTION as i64 {
+        return Err(RedisError::Str("Invalid resolution (must be 0-15)"));
+    }
+    Ok((h3_to_h3ll(&h3idx), res as u8, args.collect()))
+}
+
+fn h3ll_to_reply(h3ll: u64) -> RedisResult {
+    match H3Index::new(h3ll) {
+        Ok(h3idx) => Ok(h3idx.to_string().into()),
+        Err(_err) => Err(RedisError::Str("Invalid h3idx value"))
+    }
+}
+
+///
+/// H3.PARENT h3idx res
+///
+/// Returns the H3 index of the given H3 index's parent cell at resolution res
+///
+fn h3parent_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.PARENT h3idx res";
+    let (h3ll, res, rest) = h3idx_and_res_args(args, syntax_err_msg)?;
+    if !rest.is_empty() {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+    if res > index_resolution(h3ll) {
+        return Err(RedisError::Str("res must not be higher than the h3idx resolution"));
+    }
+
+    h3ll_to_reply(index_parent(h3ll, res))
+}
+
+///
+/// H3.CHILDREN h3idx res [COUNT count]
+///
+/// Returns the H3 indices of the given H3 index's children at resolution res. Without COUNT
+/// an error is returned if there are more than 100000 children, with COUNT (at most 100000) only
+/// the first count children are returned.
+///
+fn h3children_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.CHILDREN h3idx res [COUNT count]";
+    let (h3ll, res, rest) = h3idx_and_res_args(args, syntax_err_msg)?;
+    if res < index_resolution(h3ll) {
+        return Err(RedisError::Str("res must not be lower than the h3idx resolution"));
+    }
+
+    let mut rest = rest.into_iter();
+    let limit = match rest.next_string() {
+        Ok(arg) if arg.to_uppercase() == "COUNT" => {
+            let count = rest.next_i64()?;
+            if count <= 0 || count as u64 > MAX_CHILDREN {
+                return Err(RedisError::Str("Invalid COUNT (must be 1-100000)"));
+            }
+            count as usize
+        },
+        Ok(_) => return Err(RedisError::Str(syntax_err_msg)),
+        Err(_) => {
+            if index_children_count(h3ll, res) > MAX_CHILDREN {
+                return Err(RedisError::Str("too many children (more than 100000), use COUNT to limit them"));
+            }
+            MAX_CHILDREN as usize
+        }
+    };
+    if rest.next().is_some() {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut children: Vec<RedisValue> = Vec::new();
+    for child in index_children(h3ll, res, limit) {
+        children.push(h3ll_to_reply(child)?);
+    }
+    Ok(children.into())
+}
+
+///
+/// H3.CENTERCHILD h3idx res
+///
+/// Returns the H3 index of the given H3 index's center child cell at resolution res
+///
+fn h3centerchild_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.CENTERCHILD h3idx res";
+    let (h3ll, res, rest) = h3idx_and_res_args(args, syntax_err_msg)?;
+    if !rest.is_empty() {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+    if res < index_resolution(h3ll) {
+        return Err(RedisError::Str("res must not be lower than the h3idx resolution"));
+    }
+
+    h3ll_to_reply(index_center_child(h3ll, res))
+}
+
+///
+/// H3.RESOLUTION h3idx
+///
+/// Returns the resolution of the given H3 index
+///
+fn h3resolution_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() != 2 {
+        return Err(RedisError::Str("syntax error. Try H3.RESOLUTION h3idx"));
+    }
+
+    match str_to_h3(&args[1]) {
+        Ok(h3idx) => Ok((index_resolution(h3_to_h3ll(&h3idx)) as i64).into()),
+        Err(_err) => Err(RedisError::Str("Invalid h3idx value"))
+    }
+}
+
+///
+/// H3.INFO h3idx
+///
+/// Returns the resolution, base cell and pentagon flag of the given H3 index as an array of
+/// field/value pairs
+///
+fn h3info_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() != 2 {
+        return Err(RedisError::Str("syntax error. Try H3.INFO h3idx"));
+    }
+
+    let h3ll = match str_to_h3(&args[1]) {
+        Ok(h3idx) => h3_to_h3ll(&h3idx),
+        Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+    };
+    let info: Vec<RedisValue> = vec![
+        "resolution".into(),
+        RedisValue::Integer(index_resolution(h3ll) as i64),
+        "basecell".into(),
+        RedisValue::Integer(index_base_cell(h3ll) as i64),
+        "pentagon".into(),
+        RedisValue::Integer(index_is_pentagon(h3ll) as i64),
+    ];
+    Ok(info.into())
+}
+
+// the max k for the grid traversal commands (a k-ring of 500 has 751501 cells)
+const MAX_K: i64 = 500;
+// the max k for searching the members of a k-ring (a k-ring of 100 has 30301 cells), which needs
+// a query per range of adjacent cells
+const MAX_SEARCH_K: i64 = 100;
+
+/// parse the h3idx and k args shared by H3.KRING and H3.HEXRING
+fn h3idx_and_k_args(args: Vec<String>, syntax_err_msg: &'static str) -> Result<(H3Index, i32, Vec<String>), RedisError> {
+    if args.len() < 3 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let h3idx = match str_to_h3(&args.next_string()?) {
+        Ok(h3idx) => h3idx,
+        Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+    };
+    let k = args.next_i64()?;
+    if k < 0 || k > MAX_K {
+        return Err(RedisError::Str("Invalid k (must be 0-500)"));
+    }
+    Ok((h3idx, k as i32, args.collect()))
+}
+
+/// parse the two h3idx args shared by H3.GRIDDISTANCE and H3.GRIDPATH
+fn two_h3idx_args(args: Vec<String>, syntax_err_msg: &'static str) -> Result<(H3Index, H3Index), RedisError> {
+    if args.len() != 3 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    match (str_to_h3(&args[1]), str_to_h3(&args[2])) {
+        (Ok(h3idx1), Ok(h3idx2)) => Ok((h3idx1, h3idx2)),
+        _ => Err(RedisError::Str("Invalid h3idx value"))
+    }
+}
+
+///
+/// H3.KRING h3idx k [WITHDISTANCES]
+///
+/// Returns the H3 indices of all cells within grid distance k of the given H3 index's cell,
+/// with WITHDISTANCES each index is followed by its grid distance and indices are ordered by it
+///
+fn h3kring_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.KRING h3idx k [WITHDISTANCES]";
+    let (h3idx, k, rest) = h3idx_and_k_args(args, syntax_err_msg)?;
+
+    match rest.first().map(|arg| arg.to_uppercase()) {
+        None => {
+            let cells: Vec<String> = h3idx.k_ring(k).iter().map(|cell| cell.to_string()).collect();
+            Ok(cells.into())
+        },
+        Some(ref arg) if arg == "WITHDISTANCES" && rest.len() == 1 => {
+            let mut cells = h3idx.k_ring_distances(k);
+            cells.sort_by_key(|(_, dist)| *dist);
+            let mut reply: Vec<RedisValue> = Vec::with_capacity(cells.len() * 2);
+            for (cell, dist) in cells {
+                reply.push(cell.to_string().into());
+                reply.push(RedisValue::Integer(dist as i64));
+            }
+            Ok(reply.into())
+        },
+        Some(_) => Err(RedisError::Str(syntax_err_msg))
+    }
+}
+
+///
+/// H3.HEXRING h3idx k
+///
+/// Returns the H3 indices of the cells at exactly grid distance k from the given H3 index's
+/// cell (the hollow ring), an error is returned if a pentagon is encountered
+///
+fn h3hexring_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.HEXRING h3idx k";
+    let (h3idx, k, rest) = h3idx_and_k_args(args, syntax_err_msg)?;
+    if !rest.is_empty() {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    match h3idx.hex_ring(k) {
+        Ok(cells) => {
+            let cells: Vec<String> = cells.iter().map(|cell| cell.to_string()).collect();
+            Ok(cells.into())
+        },
+        Err(_err) => Err(RedisError::Str("error trying to get hex ring (pentagon encountered)"))
+    }
+}
+
+///
+/// H3.GRIDDISTANCE h3idx1 h3idx2
+///
+/// Returns the grid distance (number of cell steps) between two H3 indices of the same
+/// resolution
+///
+fn h3griddistance_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    let (h3idx1, h3idx2) = two_h3idx_args(args, "syntax error. Try H3.GRIDDISTANCE h3idx1 h3idx2")?;
+
+    match h3idx1.distance(&h3idx2) {
+        Ok(dist) => Ok((dist as i64).into()),
+        Err(_err) => Err(RedisError::Str("error trying to get grid distance"))
+    }
+}
+
+///
+/// H3.GRIDPATH h3idx1 h3idx2
+///
+/// Returns the H3 indices of the line of cells from h3idx1 to h3idx2 (inclusive), which must
+/// have the same resolution
+///
+fn h3gridpath_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    let (h3idx1, h3idx2) = two_h3idx_args(args, "syntax error. Try H3.GRIDPATH h3idx1 h3idx2")?;
+
+    match h3idx1.line(&h3idx2) {
+        Ok(cells) => {
+            let cells: Vec<String> = cells.iter().map(|cell| cell.to_string()).collect();
+            Ok(cells.into())
+        },
+        Err(_err) => Err(RedisError::Str("error trying to get grid path"))
+    }
+}
+
+/// get the cells of the k-ring around h3idx as H3Index values as u64, grouped by their grid
+/// distance from h3idx (the first group being h3idx itself)
+fn kring_rings(h3idx: &H3Index, k: i32) -> Vec<Vec<u64>> {
+    let mut rings: Vec<Vec<u64>> = (0..=k).map(|_| vec![]).collect();
+    for (cell, dist) in h3idx.k_ring_distances(k) {
+        rings[dist as usize].push(h3_to_h3ll(&cell));
+    }
+    rings
+}
+
+///
+/// H3.NEARBY key h3idx k [WITHINDICES] [GROUPBYRING] [LIMIT offset count]
+///     [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]
+///
+/// Returns the elements contained in the k-ring of cells around the given H3 index's cell (at
+/// its resolution), ordered by ring (0 is the cell itself, 1 its neighbors, etc.). With
+/// GROUPBYRING an array of elements is returned for each ring (which a FeatureCollection can't
+/// be grouped by). LIMIT applies to the elements across all rings, k can be 0-100.
+///
+fn h3nearby_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.NEARBY key h3idx k [WITHINDICES] [GROUPBYRING] [LIMIT offset count] \
+        [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]";
+    if args.len() < 4 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let h3idx = match str_to_h3(&args.next_string()?) {
+        Ok(h3idx) => h3idx,
+        Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+    };
+    let k = args.next_i64()?;
+    if k < 0 || k > MAX_SEARCH_K {
+        return Err(RedisError::Str("Invalid k (must be 0-100)"));
+    }
+    let mut withindices = false;
+    let mut groupbyring = false;
+    let mut format = ReplyFormat::Array;
+    let mut withboundary = false;
+    let mut boundaryres: Option<u8> = None;
+    let mut offset: usize = 0;
+    let mut count: Option<usize> = None;
+
+    while let Ok(arg) = args.next_string() {
+        match arg.to_uppercase().as_str() {
+            "WITHINDICES" => withindices = true,
+            "GROUPBYRING" => groupbyring = true,
+            "FORMAT" => format = next_format_arg(&mut args)?,
+            "WITHBOUNDARY" => withboundary = true,
+            "BOUNDARYRES" => boundaryres = Some(next_res_arg(&mut args)? as u8),
+            "LIMIT" => {
+                let (o, c) = (args.next_i64()?, args.next_i64()?);
+                if o < 0 {
+                    return Err(RedisError::Str(syntax_err_msg));
+                }
+                offset = o as usize;
+                // like ZRANGEBYSCORE, a negative count means all elements from offset
+                count = if c < 0 { None } else { Some(c as usize) };
+            },
+            _ => return Err(RedisError::Str(syntax_err_msg))
+        }
+    }
+
+    if groupbyring && format == ReplyFormat::GeoJson {
+        return Err(RedisError::Str("GROUPBYRING can't be used with FORMAT GEOJSON"));
+    }
+    let boundary = boundary_option(withboundary, boundaryres)?;
+    let mut reply = MembersReply::new(format, withindices, boundary)?;
+
+    let rings = kring_rings(&h3idx, k as i32);
+    let mut groups: Vec<RedisValue> = Vec::with_capacity(rings.len());
+    let mut skipped: usize = 0;
+    let mut found: usize = 0;
+    for ring in rings {
+        // each ring is queried by ranges of adjacent cells (see merge_cell_ranges), once count
+        // members are found no more ranges are queried, the remaining rings are still replied
+        // (empty) with GROUPBYRING
+        'ranges: for (min_h3ll, max_h3ll) in merge_cell_ranges(&ring) {
+            if count == Some(found) {
+                break;
+            }
+            for (name, h3ll) in get_range_members_with_indices(ctx, &key, min_h3ll, max_h3ll)? {
+                if count == Some(found) {
+                    break 'ranges;
+                }
+                if skipped < offset {
+                    skipped += 1;
+                    continue;
+                }
+                reply.push(name, h3ll)?;
+                found += 1;
+            }
+        }
+        if groupbyring {
+            groups.push(std::mem::take(&mut reply.values).into());
+        }
+    }
+
+    if groupbyring {
+        return Ok(groups.into());
+    }
+    Ok(reply.into_reply())
+}
+
+// H3.NEAREST starts searching at this resolution, and coarsens the resolution by
+// NEAREST_RES_STEP whenever NEAREST_MAX_RINGS rings haven't been enough
+const NEAREST_START_RES: i32 = 10;
+const NEAREST_RES_STEP: i32 = 2;
+const NEAREST_MAX_RINGS: i32 = 8;
+
+/// get the cells at exactly grid distance k from center
+fn hollow_ring(center: &H3Index, k: i32) -> Vec<H3Index> {
+    if k == 0 {
+        return center.k_ring(0);
+    }
+    match center.hex_ring(k) {
+        Ok(cells) => cells,
+        // hex_ring fails near pentagons, so fall back to filtering the k-ring
+        Err(_err) => center.k_ring_distances(k).into_iter()
+            .filter(|(_, dist)| *dist == k)
+            .map(|(cell, _)| cell)
+            .collect()
+    }
+}
+
+///
+/// get_nearest_members
+///
+/// Gets at least the count members nearest to lng/lat (within maxdist meters), by expanding rings
+/// of cells around lng/lat until the rings are guaranteed to cover the count-th nearest candidate
+/// (or maxdist), the resolution is coarsened if that takes too many rings. Like with
+/// get_shape_members, members at a coarser resolution than the rings' cells aren't found.
+///
+fn get_nearest_members(ctx: &Context, key: &String, lng: f64, lat: f64, count: usize,
+                       maxdist: f64) -> Result<Vec<SearchResult>, RedisError> {
+    let mut res = NEAREST_START_RES;
+    loop {
+        let center = match GeoCoord::new(lat, lng).to_h3(res) {
+            Ok(h3idx) => h3idx,
+            Err(_err) => return Err(RedisError::Str("Invalid lng or lat value"))
+        };
+
+        let mut results: Vec<SearchResult> = Vec::new();
+        let mut dists: Vec<f64> = Vec::new();
+        let mut k = 0;
+        loop {
+            for cell in hollow_ring(&center, k) {
+                for (name, h3ll) in get_cell_members_with_indices(ctx, key, h3_to_h3ll(&cell))? {
+                    let h3idx = match H3Index::new(h3ll) {
+                        Ok(h3idx) => h3idx,
+                        Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+                    };
+                    let coord: GeoCoord = h3idx.to_geo();
+                    let dist = geohash_get_distance(lng, lat, coord.lon, coord.lat);
+                    if dist <= maxdist {
+                        results.push(SearchResult { name, h3idx, lng: coord.lon, lat: coord.lat, dist });
+                        dists.push(dist);
+                    }
+                }
+            }
+
+            let covered = kring_covered_radius(res, k);
+            if covered >= maxdist {
+                return Ok(results);
+            }
+            if dists.len() >= count {
+                dists.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+                if covered >= dists[count - 1] {
+                    return Ok(results);
+                }
+            }
+            if k == NEAREST_MAX_RINGS && res > MIN_RESOLUTION {
+                break;
+            }
+            k += 1;
+        }
+
+        res = (res - NEAREST_RES_STEP).max(MIN_RESOLUTION);
+    }
+}
+
+///
+/// H3.NEAREST key lng lat COUNT count [MAXDIST dist m|km|ft|mi] [WITHDIST] [WITHCOORD] [WITHINDICES]
+///     [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]
+///
+/// Returns the count elements nearest to lng/lat (optionally only those within MAXDIST), sorted
+/// by distance, distances are in the MAXDIST unit (meters by default)
+///
+fn h3nearest_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.NEAREST key lng lat COUNT count \
+        [MAXDIST dist m|km|ft|mi] [WITHDIST] [WITHCOORD] [WITHINDICES] \
+        [FORMAT GEOJSON [WITHBOUNDARY [BOUNDARYRES res]]]";
+    if args.len() < 6 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let (lng, lat) = match (args.next_f64(), args.next_f64()) {
+        (Ok(lng), Ok(lat)) => (lng, lat),
+        _ => return Err(RedisError::Str("Invalid lng or lat value"))
+    };
+    let mut maxdist = MAX_EARTH_DISTANCE_IN_METERS;
+    let mut to_meter: f64 = 1.0;
+
+    let mut opts = SearchOptions::new();
+    while let Ok(arg) = args.next_string() {
+        if arg.to_uppercase() == "MAXDIST" {
+            let dist = args.next_f64()?;
+            if dist < 0.0 {
+                return Err(RedisError::Str("MAXDIST cannot be negative"));
+            }
+            to_meter = unit_str_to_conversion(&args.next_string()?)?;
+            maxdist = maxdist.min(dist * to_meter);
+        } else if !opts.parse_arg(&arg, &mut args)? {
+            return Err(RedisError::Str(syntax_err_msg));
+        }
+    }
+    if opts.any {
+        return Err(RedisError::Str("ANY can't be used with H3.NEAREST"));
+    }
+    let count = match opts.count {
+        Some(count) => count,
+        None => return Err(RedisError::Str("COUNT is required for H3.NEAREST"))
+    };
+    opts.validate()?;
+
+    // keep the nearest count before applying the requested sort order
+    let mut results = get_nearest_members(ctx, &key, lng, lat, count, maxdist)?;
+    results.sort_by(|a, b| a.dist.partial_cmp(&b.dist).unwrap_or(Ordering::Equal));
+    results.truncate(count);
+    search_results_reply(results, &opts, to_meter)
+}
+
+/// parse a list of h3idx args
+fn h3idx_args<I: Iterator<Item = String>>(args: I) -> Result<Vec<u64>, RedisError> {
+    let mut h3lls: Vec<u64> = Vec::new();
+    for h3key in args {
+        match str_to_h3(&h3key) {
+            Ok(h3idx) => h3lls.push(h3_to_h3ll(&h3idx)),
+            Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+        }
+    }
+    Ok(h3lls)
+}
+
+///
+/// H3.COMPACT h3idx1 ... [h3idxN]
+///
+/// Returns the compacted set of the given H3 indices (any resolutions): cells contained in other
+/// given cells are dropped and every complete set of siblings is replaced with their parent
+///
+fn h3compact_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() < 2 {
+        return Err(RedisError::Str("syntax error. Try H3.COMPACT h3idx1 ... [h3idxN]"));
+    }
+
+    let h3lls = h3idx_args(args.into_iter().skip(1))?;
+    let mut cells: Vec<RedisValue> = Vec::new();
+    for h3ll in compact_cells(&h3lls) {
+        cells.push(h3ll_to_reply(h3ll)?);
+    }
+    Ok(cells.into())
+}
+
+///
+/// H3.UNCOMPACT res h3idx1 ... [h3idxN]
+///
+/// Returns the H3 indices of all cells at resolution res that are contained in the given
+/// H3 indices (which must not have a higher resolution than res), an error is returned if
+/// there would be more than 100000 cells
+///
+fn h3uncompact_command(_ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::Str("syntax error. Try H3.UNCOMPACT res h3idx1 ... [h3idxN]"));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let res = args.next_i64()?;
+    if res < MIN_RESOLUTION as i64 || res > MAX_RESOLUTION as i64 {
+        return Err(RedisError::Str("Invalid resolution (must be 0-15)"));
+    }
+    let res = res as u8;
+    let h3lls = h3idx_args(args)?;
+
+    let mut total: u64 = 0;
+    for h3ll in &h3lls {
+        if index_resolution(*h3ll) > res {
+            return Err(RedisError::Str("res must not be lower than the h3idx resolutions"));
+        }
+        total += index_children_count(*h3ll, res);
+    }
+    if total > MAX_CHILDREN {
+        return Err(RedisError::Str("too many cells (more than 100000)"));
+    }
+
+    let mut cells: Vec<RedisValue> = Vec::with_capacity(total as usize);
+    for h3ll in h3lls {
+        for child in index_children(h3ll, res, MAX_CHILDREN as usize) {
+            cells.push(h3ll_to_reply(child)?);
+        }
+    }
+    Ok(cells.into())
+}
+
+/// the cells whose compaction adding h3ll to a cover can change are the descendants of its
+/// parent (its siblings and their descendants) and that parent's ancestors
+fn cover_region(h3ll: u64) -> u64 {
+    index_parent(h3ll, index_resolution(h3ll).saturating_sub(1))
+}
+
+///
+/// get_cover_region_cells
+///
+/// Gets the cells of a cover key (cover keys are zsets whose members are the cells' H3 indices
+/// and whose scores are the usual H3 scores) that are in regions (see cover_region) or contain
+/// them, the ranges of adjacent regions are read with one query (see merge_cell_ranges) and each
+/// ancestor that isn't in checked yet with one exact score query.
+///
+fn get_cover_region_cells(ctx: &Context, key: &String, regions: &[u64],
+                          checked: &mut HashSet<u64>) -> Result<Vec<u64>, RedisError> {
+    let mut h3lls: Vec<u64> = Vec::new();
+    for (min_h3ll, max_h3ll) in merge_cell_ranges(regions) {
+        for (_name, h3ll) in get_range_members_with_indices(ctx, key, min_h3ll, max_h3ll)? {
+            h3lls.push(h3ll);
+        }
+    }
+    for region in regions {
+        for ancestor in index_ancestors(*region) {
+            if checked.insert(ancestor) {
+                let (_min_h3ll, ancestor_key) = index_descendant_range(ancestor);
+                for (_name, h3ll) in get_range_members_with_indices(ctx, key, ancestor_key, ancestor_key)? {
+                    h3lls.push(h3ll);
+                }
+            }
+        }
+    }
+    Ok(h3lls)
+}
+
+///
+/// H3.COVERADD key h3idx1 ... [h3idxN]
+///
+/// Adds cells (any resolutions) to the coverage stored in key and compacts it (see H3.COMPACT),
+/// returns the number of cells stored. Cover keys are zsets with the cells' H3 indices as both
+/// members and scores, so they are H3 keys too. Only the stored cells around the added ones are
+/// read: the regions of the cells that aren't stored yet are read and compacted with them until
+/// compacting doesn't produce cells in regions that haven't been read.
+///
+fn h3coveradd_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::Str("syntax error. Try H3.COVERADD key h3idx1 ... [h3idxN]"));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let mut cells: Vec<u64> = compact_cells(&h3idx_args(args)?);
+
+    let mut existing: HashSet<u64> = HashSet::new();
+    let mut read: HashSet<u64> = HashSet::new();
+    let mut checked: HashSet<u64> = HashSet::new();
+    loop {
+        // the regions of the new cells that aren't in a region that has been read already
+        let regions: HashSet<u64> = cells.iter()
+            .filter(|h3ll| !existing.contains(*h3ll))
+            .map(|h3ll| cover_region(*h3ll))
+            .filter(|region| !read.contains(region) &&
+                !index_ancestors(*region).iter().any(|ancestor| read.contains(ancestor)))
+            .collect();
+        if regions.is_empty() {
+            break;
+        }
+        let regions: Vec<u64> = regions.into_iter().collect();
+        existing.extend(get_cover_region_cells(ctx, &key, &regions, &mut checked)?);
+        read.extend(regions);
+        cells.extend(existing.iter().copied());
+        cells = compact_cells(&cells);
+    }
+
+    // only remove the cells that were compacted away and add the new ones
+    let compacted: HashSet<u64> = cells.iter().copied().collect();
+    let mut zremargs: Vec<String> = vec![key.clone()];
+    for h3ll in existing.iter().filter(|h3ll| !compacted.contains(*h3ll)) {
+        zremargs.push(format!("{:x}", h3ll));
+    }
+    let mut zaddargs: Vec<String> = vec![key.clone()];
+    for h3ll in cells.iter().filter(|h3ll| !existing.contains(*h3ll)) {
+        zaddargs.push(format!("{}", h3ll_to_score(*h3ll)));
+        zaddargs.push(format!("{:x}", h3ll));
+    }
+
+    if zremargs.len() > 1 {
+        let zremargs: Vec<&str> = zremargs.iter().map(|s| s.as_str()).collect();
+        ctx.call("zrem", &zremargs[..])?;
+    }
+    if zaddargs.len() > 1 {
+        let zaddargs: Vec<&str> = zaddargs.iter().map(|s| s.as_str()).collect();
+        ctx.call("zadd", &zaddargs[..])?;
+    }
+    // the ZREM and ZADD calls aren't replicated, so the command is
+    ctx.replicate_verbatim();
+
+    ctx.call("zcard", &[&key])
+}
+
+///
+/// H3.CONTAINS key lng lat
+///
+/// Returns the elements of key whose cells contain lng/lat, e.g. the cells of a cover key (see
+/// H3.COVERADD). The res 15 cell of lng/lat and each of its ancestors are looked up, so this
+/// takes at most 16 lookups.
+///
+fn h3contains_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() != 4 {
+        return Err(RedisError::Str("syntax error. Try H3.CONTAINS key lng lat"));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let h3ll = match (args.next_f64(), args.next_f64()) {
+        (Ok(lng), Ok(lat)) => match GeoCoord::new(lat, lng).to_h3(MAX_RESOLUTION) {
+            Ok(h3idx) => h3_to_h3ll(&h3idx),
+            Err(_err) => return Err(RedisError::Str("Invalid lng or lat value"))
+        },
+        _ => return Err(RedisError::Str("Invalid lng or lat value"))
+    };
+
+    let mut containing: Vec<RedisValue> = Vec::new();
+    for cell in std::iter::once(h3ll).chain(index_ancestors(h3ll)) {
+        for (name, _h3ll) in get_range_members_with_indices(ctx, &key, cell, cell)? {
+            containing.push(name.into());
+        }
+    }
+    Ok(containing.into())
+}
+
+///
+/// H3.ZONEADD key zoneid [RES res] lng1 lat1 ... lngN latN [HOLE lng1 lat1 ... lngN latN]
+/// H3.ZONEADD key zoneid [RES res] GEOJSON json|WKT wkt
+/// H3.ZONEADD key zoneid CELLS h3idx1 ... [h3idxN]
+///
+/// Adds a zone to the reverse geofence index (the h3zoneidx native type) in key, or adds to the
+/// zone if it exists. The zone is either one or more polygons (see H3.POLYGON), which are filled
+/// with cells at resolution res (by default like H3.POLYGON), or a set of cells of any
+/// resolutions. As with H3.POLYGON, a res at which the polygons would need more than 100000 cells
+/// is an error. The zone's cells are kept compacted, returns the number of cells stored for the
+/// zone.
+///
+fn h3zoneadd_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.ZONEADD key zoneid [RES res] lng1 lat1 ... lngN latN \
+        [HOLE lng1 lat1 ... lngN latN]|GEOJSON json|WKT wkt or H3.ZONEADD key zoneid CELLS h3idx1 ... [h3idxN]";
+    zone_add(ctx, args, syntax_err_msg)
+}
+
+/// the shared implementation of H3.ZONEADD and H3.FENCEADD
+fn zone_add(ctx: &Context, args: Vec<String>, syntax_err_msg: &'static str) -> RedisResult {
+    if args.len() < 5 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1).peekable();
+    let key = args.next_string()?;
+    let zone = args.next_string()?;
+
+    let cells: Vec<u64> = if args.peek().map(|arg| arg.to_uppercase()) == Some("CELLS".to_string()) {
+        args.next();
+        let cells = h3idx_args(args)?;
+        if cells.is_empty() {
+            return Err(RedisError::Str(syntax_err_msg));
+        }
+        cells
+    } else {
+        let mut polygon_args = PolygonArgs::new();
+        let mut res: Option<i32> = None;
+        while let Ok(arg) = args.next_string() {
+            if arg.to_uppercase() == "RES" {
+                res = Some(next_res_arg(&mut args)?);
+            } else if !polygon_args.parse_arg(&arg, &mut args)? {
+                return Err(RedisError::Str(syntax_err_msg));
+            }
+        }
+        let polygons = polygon_args.into_polygons()?;
+        let res = res.unwrap_or_else(|| polygons_resolution(&polygons));
+        let cells = get_polygon_cells(&polygons, res, false)?;
+        if cells.is_empty() {
+            return Err(RedisError::Str("the polygon doesn't contain any cells at this resolution"));
+        }
+        cells
+    };
+
+    let redis_key = ctx.open_key_writable(&key);
+    let len = match redis_key.get_value::<ZoneIndex>(&H3ZONES_TYPE)? {
+        Some(index) => index.add(&zone, &cells),
+        None => {
+            let mut index = ZoneIndex::new();
+            let len = index.add(&zone, &cells);
+            redis_key.set_value(&H3ZONES_TYPE, index)?;
+            len
+        }
+    };
+
+    ctx.replicate_verbatim();
+    Ok((len as i64).into())
+}
+
+///
+/// H3.ZONEQUERY key lng lat
+///
+/// Returns the ids of the zones in key (see H3.ZONEADD) containing lng/lat, i.e. the zones
+/// with a cell containing the res 15 cell of lng/lat, so this takes at most 16 lookups
+///
+fn h3zonequery_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() != 4 {
+        return Err(RedisError::Str("syntax error. Try H3.ZONEQUERY key lng lat"));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let h3ll = match (args.next_f64(), args.next_f64()) {
+        (Ok(lng), Ok(lat)) => match GeoCoord::new(lat, lng).to_h3(MAX_RESOLUTION) {
+            Ok(h3idx) => h3_to_h3ll(&h3idx),
+            Err(_err) => return Err(RedisError::Str("Invalid lng or lat value"))
+        },
+        _ => return Err(RedisError::Str("Invalid lng or lat value"))
+    };
+
+    let redis_key = ctx.open_key(&key);
+    let zones: Vec<RedisValue> = match redis_key.get_value::<ZoneIndex>(&H3ZONES_TYPE)? {
+        Some(index) => index.query(h3ll).into_iter().map(|zone| zone.into()).collect(),
+        None => vec![]
+    };
+    Ok(zones.into())
+}
+
+///
+/// H3.ZONEREM key zoneid
+///
+/// Removes a zone from the reverse geofence index in key, returns 1 if it was removed, 0 if
+/// it didn't exist
+///
+fn h3zonerem_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() != 3 {
+        return Err(RedisError::Str("syntax error. Try H3.ZONEREM key zoneid"));
+    }
+
+    let redis_key = ctx.open_key_writable(&args[1]);
+    let removed = match redis_key.get_value::<ZoneIndex>(&H3ZONES_TYPE)? {
+        Some(index) => {
+            let removed = index.remove(&args[2]);
+            if index.len() == 0 {
+                redis_key.delete()?;
+            }
+            removed
+        },
+        None => false
+    };
+
+    if removed {
+        ctx.replicate_verbatim();
+    }
+    Ok((removed as i64).into())
+}
+
+// the fences for the elements of key are stored in key + FENCE_KEY_SUFFIX (see H3.FENCEADD), in
+// a cluster key has to have a hash tag (e.g. {fleet}) for both to be in the same slot
+const FENCE_KEY_SUFFIX: &str = ":fences";
+// the type name of H3ZONES_TYPE, as returned by TYPE
+const H3ZONES_TYPE_NAME: &str = "h3zoneidx";
+
+///
+/// H3.FENCEADD fencekey fenceid [RES res] lng1 lat1 ... lngN latN [HOLE lng1 lat1 ... lngN latN]
+/// H3.FENCEADD fencekey fenceid [RES res] GEOJSON json|WKT wkt
+/// H3.FENCEADD fencekey fenceid CELLS h3idx1 ... [h3idxN]
+///
+/// Registers a geofence, which is a zone (see H3.ZONEADD) in the fence key of an H3 key: the
+/// fences for the elements of key are in key:fences. When H3.ADD or H3.ADDBYINDEX moves an
+/// element of key, enter, exit and dwell events for its fences are published (see
+/// publish_fence_events), dwell on every update of an element that stays inside a fence. Fences
+/// are removed with H3.ZONEREM. In a cluster, the H3 key has to have a hash tag so that its fence
+/// key is in the same slot.
+///
+fn h3fenceadd_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.FENCEADD fencekey fenceid [RES res] lng1 lat1 ... lngN latN \
+        [HOLE lng1 lat1 ... lngN latN]|GEOJSON json|WKT wkt or H3.FENCEADD fencekey fenceid CELLS h3idx1 ... [h3idxN]";
+    zone_add(ctx, args, syntax_err_msg)
+}
+
+/// check whether any fences are registered for the elements of key, a fence key holding a value
+/// of another type is just an unrelated key, so it doesn't have any
+fn has_fences(ctx: &Context, key: &str) -> Result<bool, RedisError> {
+    let fence_key = format!("{}{}", key, FENCE_KEY_SUFFIX);
+    match ctx.call("type", &[fence_key.as_str()])? {
+        RedisValue::SimpleString(key_type) => Ok(key_type == H3ZONES_TYPE_NAME),
+        _ => Ok(false)
+    }
+}
+
+/// get the (event, fence) pairs of an element moving from its previous index (None for a new
+/// element) to h3ll: it enters the fences that contain its new position but not its previous
+/// one, exits the fences that contain its previous position but not its new one and dwells in
+/// the fences that contain both. Dwell isn't gated by time, it's reported on every update that
+/// stays inside a fence, even if the element didn't move.
+fn fence_events(fences: &ZoneIndex, prev: Option<u64>, h3ll: u64) -> Vec<(&'static str, String)> {
+    let from = prev.map(|prev| fences.query(prev)).unwrap_or_default();
+    let to = fences.query(h3ll);
+    let mut events: Vec<(&'static str, String)> = from.difference(&to)
+        .map(|fence| ("exit", fence.clone()))
+        .collect();
+    events.extend(to.into_iter().map(|fence| (if from.contains(&fence) { "dwell" } else { "enter" }, fence)));
+    events
+}
+
+///
+/// publish_fence_events
+///
+/// Publishes the geofence events of elements of key moving from their previous indices (None for
+/// new elements) to new ones on the h3.fences:<key> channel, as JSON objects with the event,
+/// fence, member and h3idx (see fence_events). The caller checks has_fences first, so that its
+/// TYPE call is only made once per command.
+///
+fn publish_fence_events(ctx: &Context, key: &str, moves: &[(String, Option<u64>, u64)]) -> Result<(), RedisError> {
+    let fence_key = ctx.open_key(&format!("{}{}", key, FENCE_KEY_SUFFIX));
+    let fences = match fence_key.get_value::<ZoneIndex>(&H3ZONES_TYPE)? {
+        Some(fences) => fences,
+        None => return Ok(())
+    };
+    let channel = format!("h3.fences:{}", key);
+
+    for (name, prev, h3ll) in moves {
+        for (event, fence) in fence_events(&fences, *prev, *h3ll) {
+            let message = format!("{{\"event\":\"{}\",\"fence\":{},\"member\":{},\"h3idx\":\"{:x}\"}}",
+                                  event, json_string(fence), json_string(name), h3ll);
+            ctx.call("publish", &[channel.as_str(), message.as_str()])?;
+        }
+    }
+    Ok(())
+}
+
+///
+/// H3.HISTORY key member [FROM ts] [TO ts] [COUNT n]
+///
+/// returns the recorded positions of an element of an H3 key (see H3.CONFIG history-length),
+/// which are in its history key (the key name with a :history suffix), as an array of
+/// [h3idx, ts] pairs, oldest first, optionally only those recorded from and/or to the given times
+/// (inclusive, in ms since the epoch) and at most COUNT of them (like XRANGE)
+///
+fn h3history_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    let syntax_err_msg = "syntax error. Try H3.HISTORY key member [FROM ts] [TO ts] [COUNT n]";
+    if args.len() < 3 {
+        return Err(RedisError::Str(syntax_err_msg));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let member = args.next_string()?;
+
+    let mut from: i64 = 0;
+    let mut to: i64 = i64::MAX;
+    let mut count: usize = usize::MAX;
+    while let Ok(arg) = args.next_string() {
+        match arg.to_uppercase().as_str() {
+            "FROM" => from = args.next_i64()?,
+            "TO" => to = args.next_i64()?,
+            "COUNT" => match args.next_i64()? {
+                n if n > 0 => count = n as usize,
+                _ => return Err(RedisError::Str("COUNT must be > 0"))
+            },
+            _ => return Err(RedisError::Str(syntax_err_msg))
+        }
+    }
+
+    let redis_key = ctx.open_key(&format!("{}{}", key, HISTORY_KEY_SUFFIX));
+    let positions: Vec<RedisValue> = match redis_key.get_value::<PositionHistory>(&H3HISTORY_TYPE)? {
+        Some(history) => match history.get(&member) {
+            Some(member_history) => member_history.positions.iter()
+                .filter(|(ts, _h3ll)| *ts as i64 >= from && *ts as i64 <= to)
+                .take(count)
+                .map(|(ts, h3ll)| vec![RedisValue::from(format!("{:x}", h3ll)), (*ts as i64).into()].into())
+                .collect(),
+            None => vec![]
+        },
+        None => vec![]
+    };
+    Ok(positions.into())
+}
+
+///
+/// H3.UPDATED key member [member2 ... memberN]
+///
+/// returns the time (in ms since the epoch) each element of an H3 key was last updated by H3.ADD
+/// or H3.ADDBYINDEX while timestamps were recorded (see H3.CONFIG), from its history key (the key
+/// name with a :history suffix), nil for elements without one
+///
+fn h3updated_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() < 3 {
+        return Err(RedisError::Str("syntax error. Try H3.UPDATED key member [member2 ... memberN]"));
+    }
+
+    let redis_key = ctx.open_key(&format!("{}{}", args[1], HISTORY_KEY_SUFFIX));
+    let history = redis_key.get_value::<PositionHistory>(&H3HISTORY_TYPE)?;
+    let timestamps: Vec<RedisValue> = args[2..].iter().map(|member| {
+        match history.as_ref().and_then(|history| history.get(member)) {
+            Some(member_history) => (member_history.updated as i64).into(),
+            None => RedisValue::Null
+        }
+    }).collect();
+    Ok(timestamps.into())
+}
+
+///
+/// H3.HISTORYADD historykey member updated [ts1 h3idx1 ... tsN h3idxN]
+///
+/// restores an element's last-updated time and adds positions to its history (this is what the
+/// history key's AOF is rewritten as), which is trimmed to the history-length (if any, so that
+/// nothing is trimmed while loading the AOF before the config is set).
+///
+fn h3historyadd_command(ctx: &Context, args: Vec<String>) -> RedisResult {
+    if args.len() < 4 || args.len() % 2 != 0 {
+        return Err(RedisError::Str(
+            "syntax error. Try H3.HISTORYADD historykey member updated [ts1 h3idx1 ... tsN h3idxN]"
+        ));
+    }
+
+    let mut args = args.into_iter().skip(1);
+    let key = args.next_string()?;
+    let member = args.next_string()?;
+    let updated = match args.next_i64()? {
+        ts if ts >= 0 => ts as u64,
+        _ => return Err(RedisError::Str("Invalid ts value (must be ms since the epoch)"))
+    };
+
+    // validate all positions before changing anything
+    let mut positions: Vec<(u64, u64)> = Vec::with_capacity(args.len() / 2);
+    while args.len() > 0 {
+        let ts = match args.next_i64()? {
+            ts if ts >= 0 => ts as u64,
+            _ => return Err(RedisError::Str("Invalid ts value (must be ms since the epoch)"))
+        };
+        match str_to_h3(&args.next_string()?) {
+            Ok(h3idx) => positions.push((ts, h3_to_h3ll(&h3idx))),
+            Err(_err) => return Err(RedisError::Str("Invalid h3idx value"))
+        }
+    }
+
+    let history_length = HISTORY_LENGTH.load(AtomicOrdering::Relaxed);
+    let redis_key = ctx.open_key_writable(&key);
+    match redis_key.get_value::<PositionHistory>(&H3HISTORY_TYPE)? {
+        Some(history) => history.restore(&member, updated, &positions, history_length),
+        None => {
+            let mut history = PositionHistory::new();
+            history.restore(&member, updated, &positions, history_length);
+            redis_key.set_value(&H3HISTORY_TYPE, history)?;
+        }
+    }
+
+    ctx.replicate_verbatim();
+    Ok(RedisValue::SimpleString(String::from("OK")))
+}
+
+//////////////////////////////////////////////////////
+
+// pub extern "C" fn init(_raw_ctx: *mut rawmod::RedisModuleCtx) -> c_int {
+//     0
+// }
+
+redis_module! {
+    name: "h3",
+    version: 1,
+    data_types: [
+        H3SET_TYPE,
+        H3ZONES_TYPE,
+        H3HISTORY_TYPE,
+    ],
+    // init: init,
+    commands: [
+        ["h3.status", h3status_command, "", 0, 0, 0],
+        ["h3.config", h3config_command, "admin", 0, 0, 0],
+        ["h3.add", h3add_command, "write deny-oom", 1, 1, 1],
+        ["h3.addbyindex", h3addbyindex_command, "write deny-oom", 1, 1, 1],
+        ["h3.history", h3history_command, "readonly", 1, 1, 1],
+        ["h3.updated", h3updated_command, "readonly", 1, 1, 1],
+        ["h3.historyadd", h3historyadd_command, "write deny-oom", 1, 1, 1],
+        ["h3.index", h3index_command, "readonly", 1, 1, 1],
+        ["h3.pos", h3pos_command, "readonly", 1, 1, 1],
+        ["h3.cell", h3cell_command, "readonly", 1, 1, 1],
+        ["h3.count", h3count_command, "readonly", 1, 1, 1],
+        ["h3.cells", h3cells_command, "readonly", 1, 1, 1],
+        ["h3.countcells", h3countcells_command, "readonly", 1, 1, 1],
+        ["h3.histogram", h3histogram_command, "readonly", 1, 1, 1],
+        ["h3.dist", h3dist_command, "readonly", 1, 1, 1],
+        ["h3.rembyindex", h3rembyindex_command, "write", 1, 1, 1],
+        ["h3.radius", h3radius_command, "readonly", 1, 1, 1],
+        ["h3.radiusbyindex", h3radiusbyindex_command, "readonly", 1, 1, 1],
+        ["h3.radiusbymember", h3radiusbymember_command, "readonly", 1, 1, 1],
+        ["h3.scan", h3scan_command, "readonly", 1, 1, 1],
+        ["h3.search", h3search_command, "readonly", 1, 1, 1],
+        ["h3.searchstore", h3searchstore_command, "write deny-oom", 1, 2, 1],
+        ["h3.polygon", h3polygon_command, "readonly", 1, 1, 1],
+        ["h3.nearby", h3nearby_command, "readonly", 1, 1, 1],
+        ["h3.nearest", h3nearest_command, "readonly", 1, 1, 1],
+        ["h3.setadd", h3setadd_command, "write deny-oom", 1, 1, 1],
+        ["h3.setrem", h3setrem_command, "write", 1, 1, 1],
+        ["h3.setindex", h3setindex_command, "readonly", 1, 1, 1],
+        ["h3.setcell", h3setcell_command, "readonly", 1, 1, 1],
+        ["h3.setcard", h3setcard_command, "readonly", 1, 1, 1],
+        ["h3.fromlonlat", h3fromlonlat_command, "readonly", 0, 0, 0],
+        ["h3.tolonlat", h3tolonlat_command, "readonly", 0, 0, 0],
+        ["h3.boundary", h3boundary_command, "readonly", 0, 0, 0],
+        ["h3.parent", h3parent_command, "readonly", 0, 0, 0],
+        ["h3.children", h3children_command, "readonly", 0, 0, 0],
+        ["h3.centerchild", h3centerchild_command, "readonly", 0, 0, 0],
+        ["h3.resolution", h3resolution_command, "readonly", 0, 0, 0],
+        ["h3.info", h3info_command, "readonly", 0, 0, 0],
+        ["h3.kring", h3kring_command, "readonly", 0, 0, 0],
+        ["h3.hexring", h3hexring_command, "readonly", 0, 0, 0],
+        ["h3.griddistance", h3griddistance_command, "readonly", 0, 0, 0],
+        ["h3.gridpath", h3gridpath_command, "readonly", 0, 0, 0],
+        ["h3.compact", h3compact_command, "readonly", 0, 0, 0],
+        ["h3.uncompact", h3uncompact_command, "readonly", 0, 0, 0],
+        ["h3.coveradd", h3coveradd_command, "write deny-oom", 1, 1, 1],
+        ["h3.contains", h3contains_command, "readonly", 1, 1, 1],
+        ["h3.zoneadd", h3zoneadd_command, "write deny-oom", 1, 1, 1],
+        ["h3.zonequery", h3zonequery_command, "readonly", 1, 1, 1],
+        ["h3.zonerem", h3zonerem_command, "write", 1, 1, 1],
+        ["h3.fenceadd", h3fenceadd_command, "write deny-oom", 1, 1, 1],
+    ],
+}
+
+//////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use redis_module::RedisValue;
+
+    use crate::h3util::index_sort_key;
+
+    use super::*;
+
+    fn run_status() -> RedisResult {
+        h3status_command(
+            &Context::dummy(),
+            vec![],
+        )
+    }
+
+    #[test]
+    fn test_merge_cell_ranges() {
+        // all 7 res 15 siblings of 8f1e9a0ec840645 merge into a single range
+        let parent_bits: u64 = 0x08f1e9a0ec840645 & !7;
+        let siblings: Vec<u64> = (0..7).map(|digit| parent_bits | digit).collect();
+        let key = index_sort_key(parent_bits);
+        assert_eq!(merge_cell_ranges(&siblings), vec![(key, key | 6)]);
+
+        // but siblings with a gap between them don't
+        let gapped = vec![parent_bits | 2, parent_bits];
+        assert_eq!(merge_cell_ranges(&gapped), vec![(key, key), (key | 2, key | 2)]);
+
+        // nor do the last child of a cell and the first child of the cell after it, as the sort key
+        // of the first res 14 parent is between them
+        let next_parent_bits = parent_bits + (1 << 3);
+        let neighbors = vec![next_parent_bits, parent_bits | 6];
+        let next_key = index_sort_key(next_parent_bits);
+        assert_eq!(merge_cell_ranges(&neighbors), vec![(key | 6, key | 6), (next_key, next_key)]);
+
+        // the res 14 parent of the siblings covers all of them
+        let parent = index_parent(parent_bits, 14);
+        assert_eq!(merge_cell_ranges(&[parent, parent_bits | 3]), vec![(key, index_sort_key(parent))]);
+
+        // the k-ring of 1 around a center child is its siblings, so it's searched with one query
+        let kring: Vec<u64> = H3Index::new(parent_bits).unwrap().k_ring(1).iter().map(h3_to_h3ll).collect();
+        assert_eq!(kring.len(), 7);
+        assert_eq!(merge_cell_ranges(&kring), vec![(key, key | 6)]);
+    }
+
+    #[test]
+    fn test_kring_rings() {
+        let h3idx = H3Index::new(0x08f1e9a0ec840645).unwrap();
+        let rings = kring_rings(&h3idx, 3);
+        let sizes: Vec<usize> = rings.iter().map(|ring| ring.len()).collect();
+        assert_eq!(sizes, vec![1, 6, 12, 18]);
+        assert_eq!(rings[0], vec![0x08f1e9a0ec840645]);
+        // every cell of a ring is a neighbor of a cell of the ring inside it
+        for dist in 1..rings.len() {
+            for cell in &rings[dist] {
+                let neighbors: Vec<u64> = H3Index::new(*cell).unwrap().k_ring(1).iter().map(h3_to_h3ll).collect();
+                assert!(rings[dist - 1].iter().any(|inner| neighbors.contains(inner)));
+            }
+        }
+        // the first ring around a center child is its 6 siblings, which are queried as one range
+        let center_child = H3Index::new(0x08f1e9a0ec840640).unwrap();
+        assert_eq!(merge_cell_ranges(&kring_rings(&center_child, 1)[1]).len(), 1);
+    }
+
+    #[test]
+    fn test_score_to_h3ll() {
+        // res 15 and lower resolution indices survive the round trip through a score
+        for h3ll in vec![0x08f1e9a0ec840645, index_parent(0x08f1e9a0ec840645, 7), 0x08001fffffffffff] {
+            assert_eq!(score_to_h3ll(h3ll_to_score(h3ll)), h3ll);
+        }
+    }
+
+    #[test]
+    fn test_radius_to_kring() {
+        let (res, k) = radius_to_kring(MAX_EARTH_DISTANCE_IN_METERS);
+        assert!(k <= 20);
+        assert_eq!(radius_to_kring(1e300), (res, k));
+        assert_eq!(radius_to_kring(f64::INFINITY), (res, k));
+        assert_eq!(radius_to_kring(0.0), (MAX_RESOLUTION, 1));
+    }
+
+    #[test]
+    fn test_ring_cells() {
+        // a thin sliver that no res 9 cell centroid is inside of
+        let ring = vec![(13.0, 38.0), (13.05, 38.0), (13.05, 38.00001)];
+        let border: Vec<u64> = ring_cells(&ring, 9).unwrap().iter().map(h3_to_h3ll).collect();
+        let midpoint = GeoCoord::new(38.0, 13.025).to_h3(9).unwrap();
+        assert!(border.contains(&h3_to_h3ll(&midpoint)));
+        // a 5 km edge crosses more than a handful of res 9 cells (edges of about 175 m)
+        assert!(border.len() > 20);
+    }
+
+    #[test]
+    fn test_h3set() {
+        let h3ll: u64 = 0x08f1e9a0ec840645;
+        let parent = index_parent(h3ll, 9);
+        let sibling = index_center_child(index_parent(h3ll, 14), 15);
+        let mut set = H3Set::new();
+        assert!(set.insert(String::from("a"), h3ll));
+        assert!(set.insert(String::from("b"), parent));
+        assert!(set.insert(String::from("c"), sibling));
+        assert_eq!(set.len(), 3);
+
+        // a cell's members are the members of its descendants and itself, in index order
+        assert_eq!(set.cell_members(parent).len(), 3);
+        assert_eq!(set.cell_members(h3ll), vec![(String::from("a"), h3ll)]);
+
+        // moving a member doesn't add it again
+        assert!(!set.insert(String::from("a"), sibling));
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.get("a"), Some(sibling));
+        assert!(set.cell_members(h3ll).is_empty());
+        assert_eq!(set.cell_members(sibling).len(), 2);
+
+        assert_eq!(set.remove("b"), Some(parent));
+        assert_eq!(set.remove("b"), None);
+        assert_eq!(set.cell_members(parent).len(), 2);
+        assert_eq!(set.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_histogram_counts() {
+        let h3ll: u64 = 0x08f1e9a0ec840645;
+        let cell = index_parent(h3ll, 9);
+        let sibling = index_center_child(index_parent(h3ll, 14), 15);
+        // in index order, the res 7 member sorts after the res 9 cell's descendants
+        let members = vec![
+            (String::from("a"), sibling),
+            (String::from("b"), h3ll),
+            (String::from("c"), cell),
+            (String::from("d"), index_parent(h3ll, 7)),
+        ];
+        assert_eq!(histogram_counts(&members, 9), vec![(cell, 3)]);
+        assert_eq!(histogram_counts(&members, 7), vec![(index_parent(h3ll, 7), 4)]);
+    }
+
+    #[test]
+    fn test_split_member_options() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() };
+        let options = ["FORMAT", "WITHBOUNDARY", "BOUNDARYRES"];
+
+        // without options all args are elements, even those named like an option
+        assert_eq!(split_member_options(args(&["a", "withboundary"]), &options),
+                   (args(&[]), args(&["a", "withboundary"])));
+        assert_eq!(split_member_options(args(&["format", "geojson", "members", "a"]), &options),
+                   (args(&["format", "geojson"]), args(&["a"])));
+        assert_eq!(split_member_options(args(&["MEMBERS", "format"]), &options),
+                   (args(&[]), args(&["format"])));
+        // and without a MEMBERS followed by elements, all args are elements too
+        assert_eq!(split_member_options(args(&["format"]), &options), (args(&[]), args(&["format"])));
+        assert_eq!(split_member_options(args(&["FORMAT", "a"]), &options), (args(&[]), args(&["FORMAT", "a"])));
+        assert_eq!(split_member_options(args(&["FORMAT", "MEMBERS"]), &options),
+                   (args(&[]), args(&["FORMAT", "MEMBERS"])));
+
+        // the same for the H3.INDEX options
+        let options = ["WITHBOUNDARY", "BOUNDARYRES"];
+        assert_eq!(split_member_options(args(&["withboundary"]), &options), (args(&[]), args(&["withboundary"])));
+        assert_eq!(split_member_options(args(&["withboundary", "boundaryres", "9", "members", "a"]), &options),
+                   (args(&["withboundary", "boundaryres", "9"]), args(&["a"])));
+        assert_eq!(split_member_options(args(&["format", "members", "a"]), &options),
+                   (args(&[]), args(&["format", "members", "a"])));
+    }
+
+    #[test]
+    fn test_index_children() {
+        // base cell 0 is a hexagon, base cell 4 is a pentagon
+        let hexagon: u64 = 0x08001fffffffffff;
+        let pentagon: u64 = 0x08009fffffffffff;
+        assert!(!index_is_pentagon(hexagon));
+        assert!(index_is_pentagon(pentagon));
+
+        for res in 1..4 {
+            for h3ll in vec![hexagon, pentagon] {
+                let children = index_children(h3ll, res, usize::MAX);
+                assert_eq!(children.len() as u64, index_children_count(h3ll, res));
+                assert_eq!(children[0], index_center_child(h3ll, res));
+                assert!(children.iter().all(|child| index_parent(*child, 0) == h3ll));
+            }
+        }
+        assert!(index_is_pentagon(index_center_child(pentagon, 5)));
+    }
+
+    #[test]
+    fn test_compact_cells() {
+        let hexagon: u64 = 0x08001fffffffffff;
+        let mut cells = index_children(hexagon, 2, usize::MAX);
+        // a cell that's contained in another is dropped
+        cells.push(index_center_child(cells[10], 5));
+        assert_eq!(compact_cells(&cells), vec![hexagon]);
+
+        // an incomplete set of siblings isn't compacted
+        cells.remove(0);
+        assert_eq!(compact_cells(&cells).len(), 6 + 6);
+    }
+
+    #[test]
+    fn test_parse_polygons() {
+        let square = Polygon {
+            outer: vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)],
+            holes: vec![],
+        };
+        let geojson = r#"{"type":"Polygon","coordinates":[[[0,0],[1,0],[1,1],[0,1],[0,0]]]}"#;
+        assert_eq!(parse_geojson(geojson), Ok(vec![square.clone()]));
+        assert_eq!(parse_wkt("POLYGON((0 0, 1 0, 1 1, 0 1, 0 0))"), Ok(vec![square.clone()]));
+
+        // multipolygons in a feature, holes
+        let feature = r#"{"type":"Feature","properties":{},"geometry":{"type":"MultiPolygon",
+            "coordinates":[[[[0,0],[1,0],[1,1],[0,1],[0,0]]],[[[2,2],[3,2],[3,3],[2,2]]]]}}"#;
+        assert_eq!(parse_geojson(feature).map(|polygons| polygons.len()), Ok(2));
+        let wkt = "MULTIPOLYGON(((0 0,4 0,4 4,0 4,0 0),(1 1,2 1,2 2,1 1)),((5 5,6 5,6 6,5 5)))";
+        let polygons = parse_wkt(wkt).unwrap();
+        assert_eq!(polygons.len(), 2);
+        assert_eq!(polygons[0].holes, vec![vec![(1.0, 1.0), (2.0, 1.0), (2.0, 2.0)]]);
+
+        // invalid rings
+        assert!(parse_wkt("POLYGON((0 0, 1 0, 1 1, 0 1))").is_err());
+        assert!(parse_wkt("POLYGON((0 0, 1 0, 0 0))").is_err());
+        assert!(parse_wkt("POLYGON((0 0, 1 0, 1 91, 0 0))").is_err());
+        assert!(parse_wkt("POLYGON((0 0, 1 0, 1 1, 0 0)").is_err());
+        assert!(parse_wkt("LINESTRING(0 0, 1 0)").is_err());
+        let nested = format!("POLYGON({}0 0{})", "(".repeat(100000), ")".repeat(100000));
+        assert!(parse_wkt(&nested).is_err());
+        assert!(parse_geojson(r#"{"type":"Polygon","coordinates":[[[0,0],[1,0],[1,"a"],[0,0]]]}"#).is_err());
+        assert!(parse_geojson(r#"{"type":"Point","coordinates":[0,0]}"#).is_err());
+        assert!(parse_wkt("POLYGON((0 0, 1 0, 0 0, 1 0, 0 0))").is_err());
+
+        // lng/lat vertices
+        let parse_args = |args: &[&str]| -> Result<Vec<Polygon>, RedisError> {
+            let mut polygon_args = PolygonArgs::new();
+            let mut args = args.iter().map(|arg| arg.to_string());
+            while let Some(arg) = args.next() {
+                assert!(polygon_args.parse_arg(&arg, &mut args)?);
+            }
+            polygon_args.into_polygons()
+        };
+        assert_eq!(parse_args(&["0", "0", "1", "0", "1", "1", "0", "1"]).ok(), Some(vec![square]));
+        assert!(parse_args(&["0", "0", "1", "0", "1", "91"]).is_err());
+        assert!(parse_args(&["0", "0", "1", "0", "inf", "1"]).is_err());
+        assert!(parse_args(&["0", "0", "1", "0", "NaN", "1"]).is_err());
+        assert!(parse_args(&["0", "0", "1", "0", "0", "0", "1", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_polyfill_cell_limit() {
+        let square = Polygon {
+            outer: vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)],
+            holes: vec![],
+        };
+        // the default resolution stays well under the cap
+        let res = polygons_resolution(&[square.clone()]);
+        assert!(polyfill_cell_estimate(&[square.bbox_size()], res) <= MAX_RES_POLYFILL_CELLS / 10.0);
+        assert!(get_polygon_cells(&[square.clone()], res, true).is_ok());
+        // but explicit finer resolutions (each has 7 times as many cells) are rejected
+        assert!(get_polygon_cells(&[square.clone()], 15, false).is_err());
+        assert!(get_polygon_cells(&[square], res + 3, false).is_err());
+    }
+
+    #[test]
+    fn test_geojson_features() {
+        let feature = geojson_feature(&point_to_geojson(13.361389, 38.115556),
+                                      &[("member", json_string("Palermo \"PA\"")), ("distance", "0.5".to_string())]);
+        assert_eq!(feature, r#"{"type":"Feature","geometry":{"type":"Point","coordinates":[13.361389,38.115556]},"properties":{"member":"Palermo \"PA\"","distance":0.5}}"#);
+        assert_eq!(geojson_feature_collection(&[]), r#"{"type":"FeatureCollection","features":[]}"#);
+    }
+
+    #[test]
+    fn test_valid_lng_lat() {
+        assert_eq!(valid_lng_lat(13.361389, 38.115556, false), Some((13.361389, 38.115556)));
+        assert_eq!(valid_lng_lat(180.0, -85.0, false), Some((180.0, -85.0)));
+        assert_eq!(valid_lng_lat(200.0, 10.0, false), None);
+        assert_eq!(valid_lng_lat(200.0, 10.0, true), Some((-160.0, 10.0)));
+        assert_eq!(valid_lng_lat(-540.0, 10.0, true), Some((-180.0, 10.0)));
+        assert_eq!(valid_lng_lat(10.0, 89.0, true), None);
+        assert_eq!(valid_lng_lat(f64::NAN, 10.0, true), None);
+    }
+
+    #[test]
+    fn test_element_moves() {
+        let h3ll: u64 = 0x08f1e9a0ec840645;
+        let parent = index_parent(h3ll, 7);
+        let entries = vec![(String::from("a"), h3ll), (String::from("b"), parent), (String::from("a"), parent)];
+        let prev = vec![None, Some(H3Index::new(h3ll).unwrap()), None];
+
+        // the second "a" moves from where the first one put it
+        assert_eq!(element_moves(&entries, &prev, false, false), vec![
+            (String::from("a"), None, h3ll),
+            (String::from("b"), Some(h3ll), parent),
+            (String::from("a"), Some(h3ll), parent),
+        ]);
+        assert_eq!(element_moves(&entries, &prev, true, false), vec![(String::from("a"), None, h3ll)]);
+        assert_eq!(element_moves(&entries, &prev, false, true), vec![(String::from("b"), Some(h3ll), parent)]);
+    }
+
+    #[test]
+    fn test_position_tracking_config() {
+        assert!(!tracking_positions());
+        set_config("history-length", "10").unwrap();
+        assert_eq!(get_config("history-length").unwrap(), "10");
+        assert!(tracking_positions());
+        set_config("history-length", "0").unwrap();
+
+        set_config("timestamps", "YES").unwrap();
+        assert_eq!(get_config("timestamps").unwrap(), "yes");
+        assert!(tracking_positions());
+        set_config("timestamps", "no").unwrap();
+        assert!(!tracking_positions());
+
+        assert!(set_config("timestamps", "maybe").is_err());
+        assert!(set_config("history-length", "-1").is_err());
+    }
+
+    #[test]
+    fn test_position_history() {
+        let mut history = PositionHistory::new();
+        history.record("a", 1, 300, 0);
+        assert_eq!(history.get("a").unwrap().updated, 300);
+        assert!(history.get("a").unwrap().positions.is_empty());
+
+        // positions are kept in time order and trimmed to the latest ones
+        history.record("a", 2, 200, 2);
+        history.record("a", 3, 400, 2);
+        history.record("a", 4, 100, 2);
+        let member_history = history.get("a").unwrap();
+        assert_eq!(member_history.updated, 400);
+        assert_eq!(member_history.positions.iter().copied().collect::<Vec<_>>(), vec![(200, 2), (400, 3)]);
+
+        history.restore("b", 50, &[(50, 5), (10, 6)], 0);
+        assert_eq!(history.get("b").unwrap().positions.iter().copied().collect::<Vec<_>>(), vec![(10, 6), (50, 5)]);
+        assert_eq!(history.len(), 2);
+
+        // restored positions are trimmed too, unless there's no limit
+        history.restore("b", 60, &[(60, 7), (30, 8)], 3);
+        assert_eq!(history.get("b").unwrap().positions.iter().copied().collect::<Vec<_>>(), vec![(30, 8), (50, 5), (60, 7)]);
+
+        assert!(history.remove("a"));
+        assert!(!history.remove("a"));
+        assert!(history.get("a").is_none());
+        assert_eq!(history.len(), 1);
+    }
+
+    #[test]
+    fn test_search_options() {
+        let parse = |args: &[&str]| -> Result<SearchOptions, RedisError> {
+            let mut opts = SearchOptions::new();
+            let mut args = args.iter().map(|arg| arg.to_string());
+            while let Some(arg) = args.next() {
+                if !opts.parse_arg(&arg, &mut args)? {
+                    return Err(RedisError::Str("syntax error"));
+                }
+            }
+            opts.validate()?;
+            Ok(opts)
+        };
+
+        let opts = parse(&["withdist", "WITHCOORD", "count", "5", "desc"]).ok().unwrap();
+        assert!(opts.withdist && opts.withcoord && !opts.withindices);
+        assert_eq!(opts.count, Some(5));
+        assert!(opts.sort == SortOrder::Desc);
+        assert_eq!(opts.stop_at(), None);
+        // COUNT without ANY sorts by distance, with ANY the search can stop early
+        assert!(parse(&["COUNT", "5"]).ok().unwrap().sort == SortOrder::Asc);
+        let opts = parse(&["COUNT", "5", "ANY"]).ok().unwrap();
+        assert!(opts.sort == SortOrder::None);
+        assert_eq!(opts.stop_at(), Some(5));
+
+        assert!(parse(&["ANY"]).is_err());
+        assert!(parse(&["COUNT", "0"]).is_err());
+        assert!(parse(&["WITHBOUNDARY"]).is_err());
+        assert!(parse(&["FORMAT", "GEOJSON", "WITHBOUNDARY", "BOUNDARYRES", "16"]).is_err());
+        assert!(parse(&["FORMAT", "GEOJSON", "WITHBOUNDARY"]).is_ok());
+        assert!(parse(&["STOREDIST"]).is_err());
+    }
+
+    #[test]
+    fn test_radius_around_index() {
+        // the centroid of a res 9 index is the center of the search
+        let center = index_parent(0x08f1e9a0ec840645, 9);
+        let coord: GeoCoord = H3Index::new(center).unwrap().to_geo();
+        let shape = SearchShape::Radius(1000.0);
+        let cells: Vec<u64> = shape.cover_cells(coord.lon, coord.lat).unwrap().iter().map(h3_to_h3ll).collect();
+        // the cover contains the center and its neighbors within the radius
+        let member = 0x08f1e9a0ec840645;
+        assert!(cells.iter().any(|cell| *cell == member || index_ancestors(member).contains(cell)));
+        let neighbor = H3Index::new(index_parent(member, 11)).unwrap().k_ring(2)[10].to_geo();
+        let dist = shape.distance_if_within(coord.lon, coord.lat, &neighbor).unwrap();
+        assert!(dist <= 1000.0);
+        assert_eq!(dist, geohash_get_distance(coord.lon, coord.lat, neighbor.lon, neighbor.lat));
+
+        // positions further away than the radius are filtered out
+        let far = GeoCoord::new(coord.lat + 0.1, coord.lon);
+        assert_eq!(shape.distance_if_within(coord.lon, coord.lat, &far), None);
+    }
+
+    fn parse_query(args: &[&str], store: bool) -> Result<SearchQuery, RedisError> {
+        let key = "key".to_string();
+        parse_search_query(&Context::dummy(), &key, args.iter().map(|arg| arg.to_string()), store, "syntax error")
+    }
+
+    #[test]
+    fn test_parse_search_query() {
+        let query = parse_query(&["FROMLONLAT", "13.5", "38.1", "BYBOX", "2", "1", "km", "ASC"], false).ok().unwrap();
+        assert_eq!((query.lng, query.lat, query.to_meter), (13.5, 38.1, 1000.0));
+        assert!(matches!(query.shape, SearchShape::Box(width, height) if width == 2000.0 && height == 1000.0));
+        assert!(query.opts.sort == SortOrder::Asc);
+
+        // the cell shapes are centered on the cell without FROM
+        let query = parse_query(&["BYKRING", "8f1e9a0ec840645", "100"], false).ok().unwrap();
+        let coord: GeoCoord = H3Index::new(0x08f1e9a0ec840645).unwrap().to_geo();
+        assert_eq!((query.lng, query.lat), (coord.lon, coord.lat));
+        assert!(matches!(query.shape, SearchShape::KRing(_, 100)));
+        assert!(matches!(parse_query(&["bycell", "8f1e9a0ec840645"], false).ok().unwrap().shape, SearchShape::Cell(_)));
+
+        assert!(parse_query(&["BYKRING", "8f1e9a0ec840645", "101"], false).is_err());
+        assert!(parse_query(&["BYKRING", "8f1e9a0ec840645", "-1"], false).is_err());
+        assert!(parse_query(&["BYRADIUS", "1", "km"], false).is_err());
+        assert!(parse_query(&["FROMLONLAT", "0", "0"], false).is_err());
+        assert!(parse_query(&["FROMLONLAT", "0", "0", "BYRADIUS", "-1", "km"], false).is_err());
+        assert!(parse_query(&["FROMLONLAT", "0", "0", "BYRADIUS", "1", "km", "BYBOX", "1", "1", "km"], false).is_err());
+        assert!(parse_query(&["FROMLONLAT", "0", "0", "FROMLONLAT", "0", "0", "BYRADIUS", "1", "km"], false).is_err());
+        assert!(parse_query(&["FROMLONLAT", "0", "0", "BYRADIUS", "1", "parsecs"], false).is_err());
+    }
+
+    #[test]
+    fn test_search_store_options() {
+        // STOREDIST is only part of the H3.SEARCHSTORE grammar
+        let args = ["FROMLONLAT", "0", "0", "BYRADIUS", "1", "km", "STOREDIST"];
+        assert!(parse_query(&args, true).ok().unwrap().opts.storedist);
+        assert!(parse_query(&args, false).is_err());
+        assert!(!parse_query(&args[..6], true).ok().unwrap().opts.storedist);
+    }
+
+    #[test]
+    fn test_sort_and_limit() {
+        let h3idx = H3Index::new(0x08f1e9a0ec840645).unwrap();
+        let results = |dists: &[f64]| -> Vec<SearchResult> {
+            dists.iter().enumerate().map(|(i, dist)| SearchResult {
+                name: i.to_string(), h3idx: h3idx.clone(), lng: 0.0, lat: 0.0, dist: *dist
+            }).collect()
+        };
+        let dists = |results: Vec<SearchResult>| -> Vec<f64> { results.iter().map(|r| r.dist).collect() };
+
+        let mut opts = SearchOptions::new();
+        assert_eq!(dists(sort_and_limit(results(&[3.0, 1.0, 2.0]), &opts)), vec![3.0, 1.0, 2.0]);
+        opts.sort = SortOrder::Desc;
+        assert_eq!(dists(sort_and_limit(results(&[3.0, 1.0, 2.0]), &opts)), vec![3.0, 2.0, 1.0]);
+        // the stored results are the count nearest ones
+        opts.sort = SortOrder::None;
+        opts.count = Some(2);
+        opts.validate().unwrap();
+        assert_eq!(dists(sort_and_limit(results(&[3.0, 1.0, 2.0]), &opts)), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_nearest_rings() {
+        let center = H3Index::new(0x08f1e9a0ec840645).unwrap();
+        // ring k has 6k cells, and together the rings make up the k-ring
+        let mut cells: Vec<u64> = Vec::new();
+        for k in 0..=3 {
+            let ring = hollow_ring(&center, k);
+            assert_eq!(ring.len(), if k == 0 { 1 } else { 6 * k as usize });
+            cells.extend(ring.iter().map(h3_to_h3ll));
+        }
+        cells.sort_unstable();
+        let mut kring: Vec<u64> = center.k_ring(3).iter().map(h3_to_h3ll).collect();
+        kring.sort_unstable();
+        assert_eq!(cells, kring);
+
+        // the radius a k-ring covers is at least the radius it was chosen for
+        for radius in [10.0, 1000.0, 50000.0] {
+            let (res, k) = radius_to_kring(radius);
+            assert!(kring_covered_radius(res, k) >= radius);
+            assert!(kring_covered_radius(res, k + 1) > kring_covered_radius(res, k));
+        }
+        assert_eq!(kring_covered_radius(15, 0), 0.0);
+    }
+
+    #[test]
+    fn test_cover_contains() {
+        // a cover made of the res 10 children of a res 9 cell is compacted to that cell
+        let point = 0x08f1e9a0ec840645;
+        let cell = index_parent(point, 9);
+        let cover = compact_cells(&index_children(cell, 10, usize::MAX));
+        assert_eq!(cover, vec![cell]);
+        // which is looked up among the point's res 15 cell and its ancestors
+        let lookups: Vec<u64> = std::iter::once(point).chain(index_ancestors(point)).collect();
+        assert_eq!(lookups.len(), 16);
+        assert!(cover.iter().all(|cell| lookups.contains(cell)));
+        // and the lookup of a cell's score only matches the cell itself
+        assert_eq!(score_to_h3ll(h3ll_to_score(cell)), cell);
+
+        // a point in a neighboring cell isn't covered
+        let neighbor = H3Index::new(cell).unwrap().k_ring(1).iter().map(h3_to_h3ll).find(|h3ll| *h3ll != cell).unwrap();
+        let outside = index_center_child(neighbor, 15);
+        assert!(!index_ancestors(outside).contains(&cell));
+    }
+
+    fn zones(ids: &[&str]) -> BTreeSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_zone_index() {
+        let point = 0x08f1e9a0ec840645;
+        let cell = index_parent(point, 9);
+        let mut index = ZoneIndex::new();
+
+        // zones are compacted, and found by any point inside them
+        assert_eq!(index.add("a", &index_children(cell, 10, usize::MAX)), 1);
+        assert_eq!(index.add("b", &[index_parent(point, 12)]), 1);
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.query(point), zones(&["a", "b"]));
+        assert_eq!(index.query(index_parent(point, 10)), zones(&["a"]));
+        assert_eq!(index.query(index_parent(point, 8)), zones(&[]));
+
+        // adding to a zone keeps its cells compacted
+        assert_eq!(index.add("b", &[index_parent(point, 9)]), 1);
+        assert_eq!(index.query(index_parent(point, 10)), zones(&["a", "b"]));
+
+        assert!(index.remove("a"));
+        assert!(!index.remove("a"));
+        assert_eq!(index.query(point), zones(&["b"]));
+        assert!(index.remove("b"));
+        assert_eq!(index.len(), 0);
+        assert_eq!(index.query(point), zones(&[]));
+    }
+
+    #[test]
+    fn test_add_options() {
+        let parse = |args: &[&str]| -> Result<(AddOptions, Vec<String>), RedisError> {
+            let mut args = args.iter().map(|arg| arg.to_string()).peekable();
+            let opts = AddOptions::parse(&mut args)?;
+            Ok((opts, args.collect()))
+        };
+
+        let (opts, rest) = parse(&["xx", "CH", "TS", "1700000000000", "13.5", "38.1", "a"]).ok().unwrap();
+        assert_eq!(opts.zaddopts, vec!["xx", "ch"]);
+        assert!(opts.xx && !opts.nx && !opts.returnprev && !opts.normalize);
+        assert_eq!(opts.ts, Some(1700000000000));
+        assert_eq!(rest, vec!["13.5", "38.1", "a"]);
+
+        // the options end at the first arg that isn't one, even if an element is named like one
+        let (opts, rest) = parse(&["NX", "RETURNPREV", "NORMALIZE", "13.5", "38.1", "ch"]).ok().unwrap();
+        assert!(opts.nx && opts.returnprev && opts.normalize && opts.ts.is_none());
+        assert_eq!(rest, vec!["13.5", "38.1", "ch"]);
+
+        assert!(parse(&["NX", "XX", "13.5", "38.1", "a"]).is_err());
+        assert!(parse(&["TS", "-1", "13.5", "38.1", "a"]).is_err());
+        assert!(parse(&["TS", "now", "13.5", "38.1", "a"]).is_err());
+        assert!(parse(&["TS"]).is_err());
+    }
+
+    #[test]
+    fn test_fence_events() {
+        let point = 0x08f1e9a0ec840645;
+        let mut fences = ZoneIndex::new();
+        fences.add("big", &[index_parent(point, 7)]);
+        fences.add("small", &[index_parent(point, 12)]);
+        let events = |prev: Option<u64>, h3ll: u64| -> Vec<(&'static str, String)> {
+            fence_events(&fences, prev, h3ll)
+        };
+        // the res 15 center children of a neighbor of the big fence's cell, and of a sibling of
+        // the point's res 8 cell in it
+        let big_cell = index_parent(point, 7);
+        let neighbor = H3Index::new(big_cell).unwrap().k_ring(1).iter().map(h3_to_h3ll).find(|h3ll| *h3ll != big_cell).unwrap();
+        let outside = index_center_child(neighbor, 15);
+        let sibling = index_children(big_cell, 8, usize::MAX).into_iter().find(|h3ll| *h3ll != index_parent(point, 8)).unwrap();
+        let inside_big = index_center_child(sibling, 15);
+
+        // new elements and elements moving in enter
+        assert_eq!(events(None, point), vec![("enter", "big".to_string()), ("enter", "small".to_string())]);
+        assert_eq!(events(Some(outside), inside_big), vec![("enter", "big".to_string())]);
+        // moving within a fence dwells, even without moving at all
+        assert_eq!(events(Some(point), point), vec![("dwell", "big".to_string()), ("dwell", "small".to_string())]);
+        // moving out exits
+        assert_eq!(events(Some(point), outside), vec![("exit", "big".to_string()), ("exit", "small".to_string())]);
+        assert_eq!(events(Some(outside), outside), vec![]);
     }
 
     #[test]
@@ -0,0 +1,23 @@
+// Helpers shared by the native data types
+
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::ptr::null_mut;
+
+use redis_module::raw as rawmod;
+
+/// emit cmd with key and args to the AOF, args are passed as a vector so that any number of them
+/// can be emitted in a single command
+pub(crate) unsafe fn emit_aof_vector(aof: *mut rawmod::RedisModuleIO, cmd: &str,
+                                     key: *mut rawmod::RedisModuleString, args: &[String]) {
+    let cmd = CString::new(cmd).unwrap();
+    // key as RedisModuleString, args as a vector of RedisModuleStrings
+    let fmt = CString::new("sv").unwrap();
+    let mut argv: Vec<*mut rawmod::RedisModuleString> = args.iter()
+        .map(|arg| rawmod::RedisModule_CreateString.unwrap()(null_mut(), arg.as_ptr() as *const c_char, arg.len()))
+        .collect();
+    rawmod::RedisModule_EmitAOF.unwrap()(aof, cmd.as_ptr(), fmt.as_ptr(), key, argv.as_mut_ptr(), argv.len());
+    for arg in argv {
+        rawmod::RedisModule_FreeString.unwrap()(null_mut(), arg);
+    }
+}